@@ -0,0 +1,104 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::task::ExecutionContext;
+
+/// Everything went fine.
+pub const EXIT_SUCCESS: u8 = 0;
+/// A task exited with a nonzero status, the remaining tasks were not run.
+pub const EXIT_TASK_FAILED: u8 = 1;
+/// Invalid command line arguments, returned by clap.
+#[allow(unused)]
+pub const EXIT_USAGE: u8 = 2;
+/// The configuration could not be loaded.
+pub const EXIT_CONFIG_ERROR: u8 = 3;
+/// The repository could not be updated or its tasks could not be loaded.
+pub const EXIT_REPOSITORY_ERROR: u8 = 4;
+/// Installing or uninstalling the autostart failed.
+pub const EXIT_INSTALL_ERROR: u8 = 5;
+/// `validate` found errors in the repository.
+pub const EXIT_VALIDATION_FAILED: u8 = 6;
+/// The group membership could not be resolved and `group_failure_policy` is `abort`.
+pub const EXIT_GROUP_RESOLUTION_FAILED: u8 = 7;
+
+const EXIT_CODES_HELP: &str = "Exit codes:
+  0  success
+  1  a task failed
+  2  invalid command line arguments
+  3  the configuration could not be loaded
+  4  the repository could not be updated or its tasks could not be loaded
+  5  installing or uninstalling the autostart failed
+  6  validate found errors in the repository
+  7  the group membership or user attributes could not be resolved and group_failure_policy is abort";
+
+/// Clones a git repository and executes the scripts in it based on its directory rules.
+#[derive(Debug, Parser)]
+#[command(version, about, after_help = EXIT_CODES_HELP)]
+pub struct Cli {
+    /// Configuration file [default: config.toml in the install directory]
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    #[arg(long, global = true, value_enum, default_value_t = LogLevel::Info)]
+    pub log_level: LogLevel,
+
+    /// Override the detected execution context (system or user)
+    #[arg(long, global = true, value_name = "CONTEXT")]
+    pub context: Option<ExecutionContext>,
+
+    /// Location of the repository checkout [default: repo in the install directory]
+    #[arg(long, global = true, value_name = "PATH")]
+    pub repo_path: Option<PathBuf>,
+
+    /// Legacy flag of older versions: install, then run, so that an Intune install command
+    /// runs the tasks right away as before
+    #[arg(long, hide = true, conflicts_with = "uninstall")]
+    pub install: bool,
+
+    /// Legacy flag of older versions, the same as the `uninstall` command
+    #[arg(long, hide = true)]
+    pub uninstall: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Update the repository and run the tasks (default)
+    Run,
+    /// Install the autostart for the current execution context
+    Install,
+    /// Remove the autostart for the current execution context
+    Uninstall,
+    /// Show the stored state of the last run
+    Status,
+    /// Show which tasks of the current checkout would run, without running them
+    Plan,
+    /// Check the current checkout for rule and dependency errors
+    Validate,
+    /// Remove the stored state, so that all OneShot tasks run again
+    Reset,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
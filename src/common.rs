@@ -1,12 +1,11 @@
-use core::str;
 use std::{
     env::{self, VarError},
     net::TcpStream,
     path::PathBuf,
-    process::Command,
 };
 
 pub const APP_NAME: &str = "RepoTaskRun";
+#[cfg(windows)]
 pub const RUN_REGKEY_NAME: &str = "RepoTaskRun";
 pub const REPO_HOST: &str = env!("REPO_HOST");
 pub const REPO_URL: &str = env!("REPO_URL");
@@ -14,23 +13,10 @@ pub const SSH_KEY: &str = include_str!("../ssh_key");
 
 pub const ENTRA_TENANT_ID: &str = env!("ENTRA_TENANT_ID");
 pub const ENTRA_CLIENT_ID: &str = env!("ENTRA_CLIENT_ID");
-pub const ENTRA_CLIENT_SECRET: &str = env!("ENTRA_CLIENT_SECRET");
-
-#[allow(unused)]
-pub fn get_upn() -> Option<String> {
-    let c = Command::new("whoami.exe").arg("/upn").output();
-
-    if let Ok(c) = c {
-        if !c.stdout.is_empty() {
-            if let Ok(s) = str::from_utf8(&c.stdout) {
-                return Some(s.split("\n").next().unwrap().to_string());
-            }
-        }
-    }
-
-    None
-}
+/// Optional, certificate auth (see `EntraAuth`) does not need a secret in the binary.
+pub const ENTRA_CLIENT_SECRET: Option<&str> = option_env!("ENTRA_CLIENT_SECRET");
 
+#[cfg(windows)]
 #[allow(unused)]
 pub fn get_user_install_path() -> Result<PathBuf, VarError> {
     let mut path = get_appdata_local()?;
@@ -40,6 +26,7 @@ pub fn get_user_install_path() -> Result<PathBuf, VarError> {
     Ok(path)
 }
 
+#[cfg(windows)]
 #[allow(unused)]
 pub fn get_system_install_path() -> Result<PathBuf, VarError> {
     let mut path = get_programdata()?;
@@ -49,6 +36,7 @@ pub fn get_system_install_path() -> Result<PathBuf, VarError> {
     Ok(path)
 }
 
+#[cfg(windows)]
 #[allow(unused)]
 pub fn get_system_repository_path() -> Result<PathBuf, VarError> {
     let mut path = get_programdata()?;
@@ -59,6 +47,7 @@ pub fn get_system_repository_path() -> Result<PathBuf, VarError> {
     Ok(path)
 }
 
+#[cfg(windows)]
 #[allow(unused)]
 pub fn get_user_repository_path() -> Result<PathBuf, VarError> {
     let mut path = get_appdata_local()?;
@@ -69,6 +58,163 @@ pub fn get_user_repository_path() -> Result<PathBuf, VarError> {
     Ok(path)
 }
 
+#[cfg(windows)]
+#[allow(unused)]
+pub fn get_system_log_path() -> Result<PathBuf, VarError> {
+    get_programdata()
+}
+
+#[cfg(windows)]
+#[allow(unused)]
+pub fn get_user_log_path() -> Result<PathBuf, VarError> {
+    get_appdata_local()
+}
+
+#[cfg(windows)]
+#[allow(unused)]
+pub fn get_ssh_config_path() -> Result<PathBuf, VarError> {
+    let mut path = get_userprofile()?;
+
+    path.push(".ssh");
+
+    Ok(path)
+}
+
+#[cfg(target_os = "linux")]
+pub const LINUX_APP_DIR_NAME: &str = "repo_task_run";
+
+#[cfg(target_os = "linux")]
+#[allow(unused)]
+pub fn get_user_install_path() -> Result<PathBuf, VarError> {
+    let mut path = get_xdg_data_home()?;
+
+    path.push(LINUX_APP_DIR_NAME);
+
+    Ok(path)
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unused)]
+pub fn get_system_install_path() -> Result<PathBuf, VarError> {
+    let mut path = PathBuf::from("/var/lib");
+
+    path.push(LINUX_APP_DIR_NAME);
+
+    Ok(path)
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unused)]
+pub fn get_system_repository_path() -> Result<PathBuf, VarError> {
+    let mut path = get_system_install_path()?;
+
+    path.push("repo");
+
+    Ok(path)
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unused)]
+pub fn get_user_repository_path() -> Result<PathBuf, VarError> {
+    let mut path = get_user_install_path()?;
+
+    path.push("repo");
+
+    Ok(path)
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unused)]
+pub fn get_system_log_path() -> Result<PathBuf, VarError> {
+    let mut path = PathBuf::from("/var/log");
+
+    path.push(LINUX_APP_DIR_NAME);
+
+    Ok(path)
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unused)]
+pub fn get_user_log_path() -> Result<PathBuf, VarError> {
+    let mut path = get_xdg_state_home()?;
+
+    path.push(LINUX_APP_DIR_NAME);
+
+    Ok(path)
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unused)]
+pub fn get_ssh_config_path() -> Result<PathBuf, VarError> {
+    let mut path = get_home()?;
+
+    path.push(".ssh");
+
+    Ok(path)
+}
+
+/// The home directory of the effective user from the passwd database, `$HOME` only if there is no entry.
+/// systemd sets `$HOME` only for units with `User=`, so the system unit runs without it.
+#[cfg(target_os = "linux")]
+pub fn get_home() -> Result<PathBuf, VarError> {
+    use std::{ffi::CStr, os::unix::ffi::OsStrExt};
+
+    let mut buf: Vec<libc::c_char> = vec![0; 16384];
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+    let rc = unsafe {
+        libc::getpwuid_r(
+            libc::geteuid(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+
+    if rc == 0 && !result.is_null() && !pwd.pw_dir.is_null() {
+        let dir = unsafe { CStr::from_ptr(pwd.pw_dir) };
+
+        if !dir.to_bytes().is_empty() {
+            return Ok(PathBuf::from(std::ffi::OsStr::from_bytes(dir.to_bytes())));
+        }
+    }
+
+    env::var("HOME").map(|h| PathBuf::from(h.trim()))
+}
+
+/// Resolves an XDG base directory, falling back to `$HOME/<fallback>` if the variable is unset or empty.
+#[cfg(target_os = "linux")]
+fn get_xdg_dir(var: &str, fallback: &str) -> Result<PathBuf, VarError> {
+    match env::var(var) {
+        Ok(p) if !p.trim().is_empty() => Ok(PathBuf::from(p.trim())),
+        _ => {
+            let mut path = get_home()?;
+            path.push(fallback);
+            Ok(path)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unused)]
+pub fn get_xdg_data_home() -> Result<PathBuf, VarError> {
+    get_xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unused)]
+pub fn get_xdg_state_home() -> Result<PathBuf, VarError> {
+    get_xdg_dir("XDG_STATE_HOME", ".local/state")
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unused)]
+pub fn get_xdg_config_home() -> Result<PathBuf, VarError> {
+    get_xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
 macro_rules! new_envar_pathgetter {
     ($name:ident, $var:literal) => {
         #[allow(unused)]
@@ -0,0 +1,279 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    common::{get_system_install_path, get_user_install_path},
+    task::ExecutionContext,
+};
+
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Runtime configuration, read from `config.toml` in the install directory.
+/// Every setting is optional, a missing file equals the default configuration.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub identity: IdentityConfig,
+    pub group_failure_policy: GroupFailurePolicy,
+    pub entra: EntraConfig,
+    pub graph: GraphConfig,
+    pub group_cache: GroupCacheConfig,
+    pub device: DeviceConfig,
+    pub groups: GroupsConfig,
+}
+
+/// What happens if the group membership cannot be looked up.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupFailurePolicy {
+    /// skip all group-restricted tasks
+    Skip,
+    /// abort the run without running any task
+    Abort,
+    /// use the cached membership of an earlier run, skip group-restricted tasks if there is none
+    #[default]
+    Cache,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    /// Maps local account names (case-insensitive) to UPNs, used if the UPN cannot be resolved natively.
+    pub upn_mapping: HashMap<String, String>,
+    /// Appended as `<account name>@<upn_suffix>` if neither a native UPN nor a mapping exists.
+    pub upn_suffix: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EntraConfig {
+    /// Also match groups the user is a member of through nested groups.
+    pub transitive_membership: bool,
+    pub auth: EntraAuth,
+    /// PEM certificate registered for the app, used for `auth = "certificate"`.
+    pub certificate: Option<PathBuf>,
+    /// PEM RSA private key of `certificate`.
+    pub private_key: Option<PathBuf>,
+    /// Keep the access token between runs, encrypted with DPAPI (Windows only).
+    pub persist_token_cache: bool,
+    /// National cloud of the tenant, selects the default endpoints.
+    pub cloud: EntraCloud,
+    /// Overrides the authority host of `cloud`, e.g. for a local mock.
+    pub authority_host: Option<String>,
+    /// Overrides the Graph host of `cloud`.
+    pub graph_host: Option<String>,
+}
+
+impl EntraConfig {
+    /// Base URL of the token endpoints, without trailing slash.
+    pub fn authority_host(&self) -> &str {
+        self.authority_host
+            .as_deref()
+            .unwrap_or(self.cloud.authority_host())
+            .trim_end_matches('/')
+    }
+
+    /// Base URL of the Graph API, without version and trailing slash.
+    pub fn graph_host(&self) -> &str {
+        self.graph_host
+            .as_deref()
+            .unwrap_or(self.cloud.graph_host())
+            .trim_end_matches('/')
+    }
+}
+
+/// Entra cloud instances with their own authority and Graph endpoints.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EntraCloud {
+    #[default]
+    Global,
+    /// US Government L4 (GCC High)
+    UsGov,
+    /// US Government L5 (DoD)
+    UsGovDod,
+    /// operated by 21Vianet
+    China,
+}
+
+impl EntraCloud {
+    fn authority_host(&self) -> &'static str {
+        match self {
+            EntraCloud::Global => "https://login.microsoftonline.com",
+            EntraCloud::UsGov | EntraCloud::UsGovDod => "https://login.microsoftonline.us",
+            EntraCloud::China => "https://login.chinacloudapi.cn",
+        }
+    }
+
+    fn graph_host(&self) -> &'static str {
+        match self {
+            EntraCloud::Global => "https://graph.microsoft.com",
+            EntraCloud::UsGov => "https://graph.microsoft.us",
+            EntraCloud::UsGovDod => "https://dod-graph.microsoft.us",
+            EntraCloud::China => "https://microsoftgraph.chinacloudapi.cn",
+        }
+    }
+}
+
+/// How the daemon app authenticates against Entra.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntraAuth {
+    /// client secret set via `ENTRA_CLIENT_SECRET` at build time
+    #[default]
+    Secret,
+    /// signed client assertion with the configured certificate
+    Certificate,
+}
+
+/// Timeouts and retries of the requests against Entra and Graph.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GraphConfig {
+    pub timeout_secs: u64,
+    /// Attempts per request, including the first one.
+    pub max_attempts: u32,
+    /// Upper bound for the delay between attempts, also if `Retry-After` asks for more.
+    pub max_retry_delay_secs: u64,
+}
+
+impl Default for GraphConfig {
+    fn default() -> Self {
+        GraphConfig {
+            timeout_secs: 30,
+            max_attempts: 4,
+            max_retry_delay_secs: 60,
+        }
+    }
+}
+
+/// The last successful group lookup of a user is kept and used if the lookup fails
+/// (with `group_failure_policy = "cache"`).
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupCacheConfig {
+    pub enabled: bool,
+    /// Older results are not used anymore.
+    pub ttl_hours: u64,
+}
+
+impl Default for GroupCacheConfig {
+    fn default() -> Self {
+        GroupCacheConfig {
+            enabled: true,
+            ttl_hours: 72,
+        }
+    }
+}
+
+/// Where group memberships are looked up, the memberships of all providers are combined.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupsConfig {
+    pub providers: Vec<GroupProviderKind>,
+    pub ldap: Option<LdapConfig>,
+    #[serde(rename = "static")]
+    pub static_: Option<StaticGroupsConfig>,
+}
+
+impl Default for GroupsConfig {
+    fn default() -> Self {
+        GroupsConfig {
+            providers: vec![GroupProviderKind::Entra],
+            ldap: None,
+            static_: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GroupProviderKind {
+    /// Entra groups via Graph, see `[entra]`
+    Entra,
+    /// groups of the user entry in an LDAP directory, see `[groups.ldap]`
+    Ldap,
+    /// groups of the account the process is running as
+    Local,
+    /// mapping file, see `[groups.static]`
+    Static,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LdapConfig {
+    /// f. e. `ldaps://dc01.contoso.com`
+    pub url: String,
+    /// Binds anonymously if not set.
+    pub bind_dn: Option<String>,
+    /// File containing the password of `bind_dn`.
+    pub bind_password_file: Option<PathBuf>,
+    pub base_dn: String,
+    /// Finds the user entry, `{upn}` is replaced by the escaped UPN.
+    #[serde(default = "LdapConfig::default_user_filter")]
+    pub user_filter: String,
+    /// Also match nested groups, uses the Active Directory in-chain matching rule.
+    #[serde(default)]
+    pub transitive: bool,
+    #[serde(default = "LdapConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl LdapConfig {
+    fn default_user_filter() -> String {
+        "(userPrincipalName={upn})".into()
+    }
+
+    fn default_timeout_secs() -> u64 {
+        10
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticGroupsConfig {
+    /// TOML file mapping UPNs and device ids to group names.
+    pub file: PathBuf,
+}
+
+/// The local device, used for group rules of system context tasks.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Entra device id, overrides the one reported by `dsregcmd /status`. Required on Linux.
+    pub id: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let content = fs::read_to_string(path)?;
+
+        Ok(toml::from_str(&content)?)
+    }
+
+    pub fn default_path(execution_context: &ExecutionContext) -> Result<PathBuf, Box<dyn Error>> {
+        let mut path = match execution_context {
+            ExecutionContext::System => get_system_install_path()?,
+            ExecutionContext::User => get_user_install_path()?,
+        };
+
+        path.push(CONFIG_FILE_NAME);
+
+        Ok(path)
+    }
+
+    pub fn load_default(execution_context: &ExecutionContext) -> Result<Self, Box<dyn Error>> {
+        let path = Self::default_path(execution_context)?;
+
+        if path.is_file() {
+            Self::load(&path)
+        } else {
+            Ok(Self::default())
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use log::{info, warn};
+#[cfg(windows)]
+use std::process::Command;
+
+use crate::{config::DeviceConfig, wildcard};
+
+/// Entra device id of this machine, lowercase. The configured id takes precedence.
+pub fn get_device_id(config: &DeviceConfig) -> Option<String> {
+    if let Some(id) = &config.id {
+        info!("Using the configured device id {}", id);
+        return Some(id.to_lowercase());
+    }
+
+    native_device_id()
+}
+
+/// Reads the `DeviceId` of the join state reported by `dsregcmd /status`.
+#[cfg(windows)]
+fn native_device_id() -> Option<String> {
+    let output = match Command::new("dsregcmd.exe").arg("/status").output() {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("Failed to run dsregcmd: {}", e);
+            return None;
+        }
+    };
+
+    let id = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "DeviceId")
+        .map(|(_, value)| value.trim().to_lowercase())
+        .filter(|id| !id.is_empty());
+
+    if id.is_none() {
+        warn!("dsregcmd reports no device id, the device is not joined to Entra");
+    }
+
+    id
+}
+
+/// There is no Entra join on Linux, the device id has to be configured.
+#[cfg(target_os = "linux")]
+fn native_device_id() -> Option<String> {
+    info!("No device id configured");
+    None
+}
+
+/// Operating system family of a device.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum OsFamily {
+    Windows,
+    Linux,
+}
+
+/// Processor architecture of the device, not of the executable.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Arch {
+    X64,
+    Arm64,
+    Other(String),
+}
+
+impl Arch {
+    fn from_name(name: &str) -> Self {
+        match name.to_lowercase().as_str() {
+            "amd64" | "x86_64" | "x64" => Arch::X64,
+            "arm64" | "aarch64" => Arch::Arm64,
+            other => Arch::Other(other.to_string()),
+        }
+    }
+}
+
+/// What the `host-`, `os-` and `arch-` rules are matched against.
+#[derive(Clone, Debug)]
+pub struct DeviceFacts {
+    pub hostname: String,
+    pub os: OsFamily,
+    /// Windows build number, f. e. `22631`; not known on Linux
+    pub os_build: Option<u32>,
+    pub arch: Arch,
+}
+
+pub trait DeviceFactsProvider {
+    fn device_facts(&self) -> DeviceFacts;
+}
+
+/// Reads the facts of the machine the process is running on.
+pub struct NativeDeviceFactsProvider();
+
+#[cfg(windows)]
+impl DeviceFactsProvider for NativeDeviceFactsProvider {
+    fn device_facts(&self) -> DeviceFacts {
+        use windows_registry::LOCAL_MACHINE;
+
+        let hostname = std::env::var("COMPUTERNAME").unwrap_or_default();
+
+        // GetVersionEx reports the version the executable is manifested for, the registry the real one
+        let os_build = LOCAL_MACHINE
+            .open("SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion")
+            .and_then(|key| key.get_string("CurrentBuildNumber"))
+            .ok()
+            .and_then(|build| build.trim().parse().ok());
+
+        // the process environment reports the emulated architecture of an x64 build on ARM64
+        let arch = LOCAL_MACHINE
+            .open("SYSTEM\\CurrentControlSet\\Control\\Session Manager\\Environment")
+            .and_then(|key| key.get_string("PROCESSOR_ARCHITECTURE"))
+            .map(|arch| Arch::from_name(&arch))
+            .unwrap_or_else(|_| Arch::from_name(std::env::consts::ARCH));
+
+        DeviceFacts {
+            hostname,
+            os: OsFamily::Windows,
+            os_build,
+            arch,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl DeviceFactsProvider for NativeDeviceFactsProvider {
+    fn device_facts(&self) -> DeviceFacts {
+        use std::ffi::CStr;
+
+        let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+
+        let (hostname, arch) = if unsafe { libc::uname(&mut uts) } == 0 {
+            let field = |f: &[libc::c_char]| {
+                unsafe { CStr::from_ptr(f.as_ptr()) }
+                    .to_string_lossy()
+                    .into_owned()
+            };
+            (field(&uts.nodename), Arch::from_name(&field(&uts.machine)))
+        } else {
+            warn!("Failed to read the system information");
+            (String::new(), Arch::from_name(std::env::consts::ARCH))
+        };
+
+        DeviceFacts {
+            hostname,
+            os: OsFamily::Linux,
+            os_build: None,
+            arch,
+        }
+    }
+}
+
+/// Returns the given facts, f. e. to evaluate the rules for another device.
+#[allow(unused)]
+pub struct StaticDeviceFactsProvider(pub DeviceFacts);
+
+impl DeviceFactsProvider for StaticDeviceFactsProvider {
+    fn device_facts(&self) -> DeviceFacts {
+        self.0.clone()
+    }
+}
+
+const WINDOWS_10_FIRST_BUILD: u32 = 10240;
+const WINDOWS_11_FIRST_BUILD: u32 = 22000;
+
+/// A `host-`, `os-` or `arch-` rule.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DeviceRule {
+    /// hostname pattern, see `wildcard::matches`
+    Host(String),
+    Os(OsFamily),
+    Windows10,
+    Windows11,
+    /// Windows build at least
+    BuildAtLeast(u32),
+    /// Windows build below
+    BuildBelow(u32),
+    Arch(Arch),
+}
+
+impl DeviceRule {
+    /// Parses the rule of a folder named `<kind>-<value>`.
+    pub fn parse(kind: &str, value: &str) -> Option<Self> {
+        let value = value.to_lowercase();
+
+        match (kind, value.as_str()) {
+            ("host", pattern) if !pattern.is_empty() => Some(DeviceRule::Host(pattern.to_string())),
+            ("os", "windows") => Some(DeviceRule::Os(OsFamily::Windows)),
+            ("os", "linux") => Some(DeviceRule::Os(OsFamily::Linux)),
+            ("os", "win10") => Some(DeviceRule::Windows10),
+            ("os", "win11") => Some(DeviceRule::Windows11),
+            // `>` and `<` are not allowed in Windows file names, `ge` and `lt` are
+            ("os", v) => {
+                if let Some(build) = v.strip_prefix("build>=").or(v.strip_prefix("build-ge-")) {
+                    build.parse().ok().map(DeviceRule::BuildAtLeast)
+                } else if let Some(build) = v.strip_prefix("build<").or(v.strip_prefix("build-lt-"))
+                {
+                    build.parse().ok().map(DeviceRule::BuildBelow)
+                } else {
+                    None
+                }
+            }
+            ("arch", "x64") => Some(DeviceRule::Arch(Arch::X64)),
+            ("arch", "arm64") => Some(DeviceRule::Arch(Arch::Arm64)),
+            _ => None,
+        }
+    }
+
+    /// The kind of rule it was parsed from.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DeviceRule::Host(_) => "host",
+            DeviceRule::Arch(_) => "arch",
+            _ => "os",
+        }
+    }
+
+    pub fn matches(&self, facts: &DeviceFacts) -> bool {
+        let windows_build = match facts.os {
+            OsFamily::Windows => facts.os_build,
+            OsFamily::Linux => None,
+        };
+
+        match self {
+            DeviceRule::Host(pattern) => wildcard::matches(pattern, &facts.hostname),
+            DeviceRule::Os(os) => &facts.os == os,
+            DeviceRule::Windows10 => windows_build
+                .is_some_and(|b| (WINDOWS_10_FIRST_BUILD..WINDOWS_11_FIRST_BUILD).contains(&b)),
+            DeviceRule::Windows11 => windows_build.is_some_and(|b| b >= WINDOWS_11_FIRST_BUILD),
+            DeviceRule::BuildAtLeast(min) => windows_build.is_some_and(|b| b >= *min),
+            DeviceRule::BuildBelow(max) => windows_build.is_some_and(|b| b < *max),
+            DeviceRule::Arch(arch) => &facts.arch == arch,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows(build: u32) -> DeviceFacts {
+        StaticDeviceFactsProvider(DeviceFacts {
+            hostname: "KIOSK-01".to_string(),
+            os: OsFamily::Windows,
+            os_build: Some(build),
+            arch: Arch::X64,
+        })
+        .device_facts()
+    }
+
+    fn rule(kind: &str, value: &str) -> DeviceRule {
+        DeviceRule::parse(kind, value).unwrap()
+    }
+
+    #[test]
+    fn windows_versions_by_build() {
+        let win10 = windows(19045);
+        let win11 = windows(22631);
+
+        assert!(rule("os", "win10").matches(&win10));
+        assert!(!rule("os", "win10").matches(&win11));
+        assert!(rule("os", "win11").matches(&win11));
+        assert!(!rule("os", "win11").matches(&win10));
+        assert!(!rule("os", "win10").matches(&windows(9600)));
+        assert!(rule("os", "windows").matches(&win10));
+        assert!(!rule("os", "linux").matches(&win10));
+    }
+
+    #[test]
+    fn build_ranges() {
+        assert!(rule("os", "build-ge-22631").matches(&windows(22631)));
+        assert!(!rule("os", "build-ge-22631").matches(&windows(22621)));
+        assert!(rule("os", "build>=22000").matches(&windows(26100)));
+        assert!(rule("os", "build-lt-22000").matches(&windows(19045)));
+        assert!(!rule("os", "build<22000").matches(&windows(22000)));
+        assert_eq!(DeviceRule::parse("os", "build-ge-new"), None);
+    }
+
+    #[test]
+    fn build_rules_never_match_linux() {
+        let linux = DeviceFacts {
+            os: OsFamily::Linux,
+            ..windows(22631)
+        };
+
+        assert!(!rule("os", "win11").matches(&linux));
+        assert!(!rule("os", "build-ge-1").matches(&linux));
+        assert!(rule("os", "linux").matches(&linux));
+    }
+
+    #[test]
+    fn host_and_arch() {
+        let facts = windows(22631);
+
+        assert!(rule("host", "kiosk-*").matches(&facts));
+        assert!(!rule("host", "pc-*").matches(&facts));
+        assert!(rule("arch", "x64").matches(&facts));
+        assert!(!rule("arch", "arm64").matches(&facts));
+        assert_eq!(Arch::from_name("aarch64"), Arch::Arm64);
+        assert_eq!(DeviceRule::parse("arch", "mips"), None);
+    }
+}
@@ -1,45 +1,202 @@
-use reqwest::blocking::Client;
+use reqwest::Url;
 use serde_json::Value;
-use std::collections::HashSet;
-
-use crate::common::{ENTRA_CLIENT_ID, ENTRA_CLIENT_SECRET, ENTRA_TENANT_ID};
-
-pub fn get_entra_groups_of_user(upn: &str) -> Result<HashSet<String>, Box<dyn std::error::Error>> {
-    let token_url = format!(
-        "https://login.microsoftonline.com/{}/oauth2/v2.0/token",
-        ENTRA_TENANT_ID
-    );
-    let client = Client::new();
-    let token_response: Value = client
-        .post(&token_url)
-        .form(&[
-            ("client_id", ENTRA_CLIENT_ID),
-            ("scope", "https://graph.microsoft.com/.default"),
-            ("client_secret", ENTRA_CLIENT_SECRET),
-            ("grant_type", "client_credentials"),
-        ])
-        .send()?
-        .json()?;
-
-    let access_token = token_response["access_token"]
-        .as_str()
-        .ok_or("Failed to get access token")?;
-
-    let graph_url = format!("https://graph.microsoft.com/v1.0/users/{}/memberOf", upn);
-    let response: Value = client
-        .get(&graph_url)
-        .bearer_auth(access_token)
-        .send()?
-        .json()?;
-
-    let groups = match response["value"].as_array() {
-        Some(groups) => groups
-            .iter()
-            .filter(|g| g["displayName"].is_string())
-            .map(|g| g["displayName"].as_str().unwrap().to_string())
-            .collect(),
-        None => HashSet::new(),
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    graph::{GraphClient, GraphError},
+    group_provider::{GroupMembership, GroupPrincipal},
+};
+
+fn graph_url(graph: &GraphClient, segments: &[&str]) -> Result<Url, GraphError> {
+    let mut url = Url::parse(&graph.base_url())
+        .map_err(|e| GraphError::Other(format!("Invalid Graph URL: {}", e)))?;
+    url.path_segments_mut()
+        .map_err(|_| GraphError::Other("Invalid Graph URL".into()))?
+        .extend(segments);
+
+    Ok(url)
+}
+
+pub fn get_entra_groups(
+    graph: &GraphClient,
+    principal: &GroupPrincipal,
+    transitive: bool,
+) -> Result<GroupMembership, GraphError> {
+    match principal {
+        GroupPrincipal::User(upn) => get_entra_groups_of_user(graph, upn, transitive),
+        GroupPrincipal::Device(device_id) => {
+            get_entra_groups_of_device(graph, device_id, transitive)
+        }
+    }
+}
+
+pub fn get_entra_groups_of_user(
+    graph: &GraphClient,
+    upn: &str,
+    transitive: bool,
+) -> Result<GroupMembership, GraphError> {
+    get_groups_of_object(graph, "users", upn, transitive).map_err(|e| match e {
+        GraphError::NotFound(_) => GraphError::NotFound(format!("user {}", upn)),
+        e => e,
+    })
+}
+
+/// Profile properties of the user, keyed by the lowercase property name.
+/// Multi-valued properties keep every value, unset properties have none.
+pub fn get_entra_user_attributes(
+    graph: &GraphClient,
+    upn: &str,
+    attributes: &HashSet<String>,
+) -> Result<HashMap<String, Vec<String>>, GraphError> {
+    let mut select: Vec<&str> = attributes.iter().map(|a| a.as_str()).collect();
+    select.sort();
+
+    let mut url = graph_url(graph, &["users", upn])?;
+    url.query_pairs_mut()
+        .append_pair("$select", &select.join(","));
+
+    let response = graph.get(url.as_str()).map_err(|e| match e {
+        GraphError::NotFound(_) => GraphError::NotFound(format!("user {}", upn)),
+        e => e,
+    })?;
+
+    let values = |value: &Value| -> Vec<String> {
+        match value {
+            Value::Null => Vec::new(),
+            Value::String(s) => vec![s.clone()],
+            Value::Array(a) => a
+                .iter()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect(),
+            v => vec![v.to_string()],
+        }
     };
 
+    Ok(response
+        .as_object()
+        .map(|object| {
+            object
+                .iter()
+                .filter(|(key, _)| !key.starts_with('@'))
+                .map(|(key, value)| (key.to_lowercase(), values(value)))
+                .collect()
+        })
+        .unwrap_or_default())
+}
+
+/// The device id is not the key of the device object, the object id is looked up first.
+pub fn get_entra_groups_of_device(
+    graph: &GraphClient,
+    device_id: &str,
+    transitive: bool,
+) -> Result<GroupMembership, GraphError> {
+    let mut url = graph_url(graph, &["devices"])?;
+    url.query_pairs_mut()
+        .append_pair(
+            "$filter",
+            &format!("deviceId eq '{}'", device_id.replace('\'', "''")),
+        )
+        .append_pair("$select", "id");
+
+    let response = graph.get(url.as_str())?;
+    let object_id = response["value"]
+        .as_array()
+        .and_then(|devices| devices.first())
+        .and_then(|device| device["id"].as_str())
+        .ok_or_else(|| GraphError::NotFound(format!("device {}", device_id)))?
+        .to_string();
+
+    get_groups_of_object(graph, "devices", &object_id, transitive).map_err(|e| match e {
+        GraphError::NotFound(_) => GraphError::NotFound(format!("device {}", device_id)),
+        e => e,
+    })
+}
+
+/// Groups of the user or device `key` in `collection`.
+fn get_groups_of_object(
+    graph: &GraphClient,
+    collection: &str,
+    key: &str,
+    transitive: bool,
+) -> Result<GroupMembership, GraphError> {
+    // transitiveMemberOf also contains the groups the object is a member of through nested groups
+    let relation = if transitive {
+        "transitiveMemberOf"
+    } else {
+        "memberOf"
+    };
+
+    // the cast to microsoft.graph.group leaves out directory roles and administrative units
+    let mut graph_url = graph_url(graph, &[collection, key, relation, "microsoft.graph.group"])?;
+    graph_url
+        .query_pairs_mut()
+        .append_pair("$select", "id,displayName")
+        .append_pair("$top", "999");
+
+    let mut groups = GroupMembership::default();
+    let mut next_url = Some(graph_url.to_string());
+
+    // the groups are paged, every page links to the next one until the last page
+    while let Some(url) = next_url {
+        let response = graph.get(&url)?;
+
+        if let Some(page) = response["value"].as_array() {
+            for group in page {
+                if let Some(id) = group["id"].as_str() {
+                    groups.insert(id, group["displayName"].as_str());
+                }
+            }
+        }
+
+        next_url = response["@odata.nextLink"].as_str().map(|u| u.to_string());
+    }
+
     Ok(groups)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{graph::mock::MockGraph, task::GroupRef};
+    use serde_json::json;
+
+    /// Two pages of groups, the first links to the second.
+    fn paged_groups(path: &str, base_url: &str) -> Value {
+        if path.starts_with("/next-page") {
+            json!({"value": [{"id": "B2", "displayName": "IT"}]})
+        } else {
+            json!({
+                "value": [{"id": "A1", "displayName": "Sales"}],
+                "@odata.nextLink": format!("{}/next-page?$skiptoken=x", base_url)
+            })
+        }
+    }
+
+    #[test]
+    fn follows_next_links() {
+        let mock = MockGraph::start(paged_groups);
+        let groups = get_entra_groups_of_user(&mock.client(), "jdoe@contoso.com", false).unwrap();
+
+        assert!(groups.contains(&GroupRef::Id("a1".into())));
+        assert!(groups.contains(&GroupRef::Name("sales".into())));
+        assert!(groups.contains(&GroupRef::Id("b2".into())));
+        assert!(groups.contains(&GroupRef::Name("it".into())));
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(
+            requests[0].starts_with("/v1.0/users/jdoe@contoso.com/memberOf/microsoft.graph.group?")
+        );
+        assert!(requests[1].starts_with("/next-page"));
+    }
+
+    #[test]
+    fn transitive_membership_uses_transitive_member_of() {
+        let mock = MockGraph::start(paged_groups);
+        let groups = get_entra_groups_of_user(&mock.client(), "jdoe@contoso.com", true).unwrap();
+
+        assert_eq!(groups.ids.len(), 2);
+        assert!(mock.requests()[0]
+            .starts_with("/v1.0/users/jdoe@contoso.com/transitiveMemberOf/microsoft.graph.group?"));
+    }
+}
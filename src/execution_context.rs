@@ -0,0 +1,130 @@
+#[cfg(windows)]
+use crate::identity::get_process_token_sid;
+use crate::task::ExecutionContext;
+use std::{error::Error, fmt::Display};
+
+/// Well-known SID of the LocalSystem account.
+#[cfg(windows)]
+pub const SYSTEM_SID: &str = "S-1-5-18";
+
+#[derive(Debug)]
+pub enum ContextDetectionError {
+    /// the SID of the process token could not be read
+    #[allow(unused)]
+    TokenSidUnavailable,
+}
+
+impl Display for ContextDetectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContextDetectionError::TokenSidUnavailable => {
+                write!(f, "Failed to get the SID of the process token")
+            }
+        }
+    }
+}
+
+impl Error for ContextDetectionError {}
+
+/// Detection runs before the logger is set up, so failures are returned instead of logged.
+pub trait ContextDetector {
+    fn detect(&self) -> Result<ExecutionContext, ContextDetectionError>;
+}
+
+/// The context given by the `--context` option, otherwise the one detected by `token`.
+pub fn detect_context(
+    context: Option<ExecutionContext>,
+    token: &dyn ContextDetector,
+) -> Result<ExecutionContext, ContextDetectionError> {
+    match context {
+        Some(c) => FixedContextDetector(c).detect(),
+        None => token.detect(),
+    }
+}
+
+/// Detects the context from the identity of the process token:
+/// SYSTEM (`S-1-5-18`) on Windows and uid 0 on Linux are the system context, everything else is the user context.
+pub struct TokenContextDetector();
+
+#[cfg(windows)]
+impl ContextDetector for TokenContextDetector {
+    fn detect(&self) -> Result<ExecutionContext, ContextDetectionError> {
+        match get_process_token_sid() {
+            Some(sid) if sid == SYSTEM_SID => Ok(ExecutionContext::System),
+            Some(_) => Ok(ExecutionContext::User),
+            None => Err(ContextDetectionError::TokenSidUnavailable),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl ContextDetector for TokenContextDetector {
+    fn detect(&self) -> Result<ExecutionContext, ContextDetectionError> {
+        if unsafe { libc::geteuid() } == 0 {
+            Ok(ExecutionContext::System)
+        } else {
+            Ok(ExecutionContext::User)
+        }
+    }
+}
+
+/// Always returns the given context, used for `--context` and in tests.
+pub struct FixedContextDetector(pub ExecutionContext);
+
+impl ContextDetector for FixedContextDetector {
+    fn detect(&self) -> Result<ExecutionContext, ContextDetectionError> {
+        Ok(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A process token which cannot be read.
+    struct UnreadableToken;
+
+    impl ContextDetector for UnreadableToken {
+        fn detect(&self) -> Result<ExecutionContext, ContextDetectionError> {
+            Err(ContextDetectionError::TokenSidUnavailable)
+        }
+    }
+
+    #[test]
+    fn fixed_detector_returns_its_context() {
+        for context in [ExecutionContext::System, ExecutionContext::User] {
+            assert_eq!(
+                FixedContextDetector(context.clone()).detect().unwrap(),
+                context
+            );
+            assert_eq!(
+                detect_context(None, &FixedContextDetector(context.clone())).unwrap(),
+                context
+            );
+        }
+    }
+
+    #[test]
+    fn override_takes_precedence() {
+        for context in [ExecutionContext::System, ExecutionContext::User] {
+            let detected = detect_context(Some(context.clone()), &UnreadableToken).unwrap();
+
+            assert_eq!(detected, context);
+        }
+    }
+
+    #[test]
+    fn unreadable_token_is_an_error_without_override() {
+        assert!(matches!(
+            detect_context(None, &UnreadableToken),
+            Err(ContextDetectionError::TokenSidUnavailable)
+        ));
+    }
+
+    #[test]
+    fn context_names_parse() {
+        assert_eq!("system".parse(), Ok(ExecutionContext::System));
+        assert_eq!("user".parse(), Ok(ExecutionContext::User));
+        assert!("admin".parse::<ExecutionContext>().is_err());
+    }
+}
@@ -6,10 +6,15 @@ use std::{error::Error, process::Command};
 use std::fs;
 use std::path::Path;
 
-use crate::common::{get_userprofile, REPO_URL, SSH_KEY};
+use crate::common::{get_ssh_config_path, REPO_URL, SSH_KEY};
+
+#[cfg(windows)]
+const SSH_KEYSCAN: &str = "ssh-keyscan.exe";
+#[cfg(target_os = "linux")]
+const SSH_KEYSCAN: &str = "ssh-keyscan";
 
 fn update_known_hosts(host: &str, known_hosts_file: &Path) -> Result<(), Box<dyn Error>> {
-    let cmd = Command::new("ssh-keyscan.exe").arg(host).output()?;
+    let cmd = Command::new(SSH_KEYSCAN).arg(host).output()?;
 
     let mut f = fs::OpenOptions::new()
         .create(true)
@@ -22,8 +27,7 @@ fn update_known_hosts(host: &str, known_hosts_file: &Path) -> Result<(), Box<dyn
 }
 
 fn add_ssh_key() -> Result<(), Box<dyn Error>> {
-    let mut config_path = get_userprofile()?;
-    config_path.push(".ssh");
+    let mut config_path = get_ssh_config_path()?;
 
     info!("[add_ssh_key] config_path: {}", config_path.display());
 
@@ -32,10 +36,10 @@ fn add_ssh_key() -> Result<(), Box<dyn Error>> {
     let payload = format!(
         r#"# BEGIN RepoRunTask
 Host github.com
-        IdentityFile {}\repo_task_run_id
+        IdentityFile {}
 # END RepoTaskRun
 "#,
-        config_path.to_str().unwrap()
+        config_path.join("repo_task_run_id").display()
     );
 
     info!("[add_ssh_key] payload: {}", payload);
@@ -55,8 +59,26 @@ Host github.com
     config_path.pop();
     config_path.push("repo_task_run_id");
 
+    #[cfg(windows)]
     fs::write(&config_path, SSH_KEY)?;
 
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        // ssh refuses to use private keys which are readable by others, the key must not be
+        // readable at any time; the mode only applies to new files, so an existing one is restricted
+        // before it is written
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&config_path)?;
+        f.set_permissions(fs::Permissions::from_mode(0o600))?;
+        f.write_all(SSH_KEY.as_bytes())?;
+    }
+
     config_path.pop();
     config_path.push("known_hosts");
 
@@ -67,8 +89,7 @@ Host github.com
 }
 
 fn remove_ssh_key() -> Result<(), Box<dyn Error>> {
-    let mut config_path = get_userprofile()?;
-    config_path.push(".ssh");
+    let mut config_path = get_ssh_config_path()?;
 
     if !config_path.exists() {
         return Ok(());
@@ -0,0 +1,724 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use log::{info, warn};
+use reqwest::{
+    blocking::{Client, RequestBuilder, Response},
+    header::RETRY_AFTER,
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use std::{
+    error::Error,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    common::{
+        get_system_install_path, get_user_install_path, ENTRA_CLIENT_ID, ENTRA_CLIENT_SECRET,
+        ENTRA_TENANT_ID,
+    },
+    config::{EntraAuth, EntraConfig, GraphConfig},
+    task::ExecutionContext,
+};
+
+const GRAPH_API_VERSION: &str = "v1.0";
+const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+/// Lifetime of a client assertion, it is only used for a single token request.
+const CLIENT_ASSERTION_LIFETIME_SECS: u64 = 600;
+/// A cached token is renewed if it expires within this margin.
+const TOKEN_EXPIRY_MARGIN_SECS: u64 = 120;
+const TOKEN_CACHE_FILE_NAME: &str = "token_cache.bin";
+
+#[derive(Debug)]
+pub enum GraphError {
+    /// no access token could be acquired (secret, certificate or consent) or it lacks permissions
+    Auth(String),
+    /// the requested object (f. e. the user) does not exist
+    NotFound(String),
+    /// Graph asks to slow down (429) or is temporarily unavailable (503)
+    Throttled { retry_after: Option<Duration> },
+    /// Graph could not be reached
+    Network(reqwest::Error),
+    /// unexpected status or response
+    Other(String),
+}
+
+/// The delay requested by the `Retry-After` header, only the delay-seconds form is supported.
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Delay before the next attempt: 1s after the first, doubling with every attempt.
+/// It saturates instead of overflowing, `max_retry_delay_secs` caps it anyway.
+fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1u64.checked_shl(attempt - 1).unwrap_or(u64::MAX))
+}
+
+/// Throttling and server errors are worth another attempt, client errors are not.
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+impl GraphError {
+    fn from_response(response: Response) -> Self {
+        let status = response.status();
+        let retry_after = retry_after(&response);
+
+        // Graph errors look like {"error": {"code": "...", "message": "..."}}
+        let message = response
+            .json::<Value>()
+            .ok()
+            .and_then(|body| body["error"]["message"].as_str().map(|m| m.to_string()))
+            .unwrap_or_default();
+
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                GraphError::Auth(format!("{}: {}", status, message))
+            }
+            StatusCode::NOT_FOUND => GraphError::NotFound(message),
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                GraphError::Throttled { retry_after }
+            }
+            _ => GraphError::Other(format!("{}: {}", status, message)),
+        }
+    }
+}
+
+impl Display for GraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphError::Auth(reason) => {
+                write!(f, "Authentication against Graph failed: {}", reason)
+            }
+            GraphError::NotFound(reason) => write!(f, "Not found in Graph: {}", reason),
+            GraphError::Throttled { retry_after } => {
+                write!(f, "Graph is throttling (retry after {:?})", retry_after)
+            }
+            GraphError::Network(e) => write!(f, "Graph is not reachable: {}", e),
+            GraphError::Other(reason) => write!(f, "Graph request failed: {}", reason),
+        }
+    }
+}
+
+impl Error for GraphError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GraphError::Network(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The access token of this run, shared by every `GraphClient`.
+static TOKEN_CACHE: Mutex<Option<CachedToken>> = Mutex::new(None);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct CachedToken {
+    /// the app the token was issued for, a token of another build is not reused
+    client_id: String,
+    /// the Graph host the token was requested for, switching clouds requests a new one
+    #[serde(default)]
+    audience: String,
+    access_token: String,
+    /// unix timestamp
+    expires_at: u64,
+}
+
+impl CachedToken {
+    fn is_valid(&self, audience: &str) -> bool {
+        match unix_now() {
+            Ok(now) => {
+                self.client_id == ENTRA_CLIENT_ID
+                    && self.audience == audience
+                    && self.expires_at > now + TOKEN_EXPIRY_MARGIN_SECS
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+fn unix_now() -> Result<u64, Box<dyn Error>> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+#[derive(Serialize)]
+struct ClientAssertionClaims<'a> {
+    aud: &'a str,
+    iss: &'a str,
+    sub: &'a str,
+    jti: String,
+    iat: u64,
+    nbf: u64,
+    exp: u64,
+}
+
+/// Builds the signed JWT of the certificate client-credentials flow.
+/// The certificate (for its thumbprint) and the RSA private key are read as PEM on every call,
+/// so they can be rotated without a rebuild.
+fn build_client_assertion(
+    token_url: &str,
+    certificate: &Path,
+    private_key: &Path,
+) -> Result<String, Box<dyn Error>> {
+    let certificate = pem::parse(fs::read(certificate)?)
+        .map_err(|e| format!("Invalid certificate {}: {}", certificate.display(), e))?;
+    let key = EncodingKey::from_rsa_pem(&fs::read(private_key)?)
+        .map_err(|e| format!("Invalid private key {}: {}", private_key.display(), e))?;
+
+    let mut header = Header::new(Algorithm::RS256);
+    header.x5t = Some(URL_SAFE_NO_PAD.encode(Sha1::digest(certificate.contents())));
+
+    let now = unix_now()?;
+    let claims = ClientAssertionClaims {
+        aud: token_url,
+        iss: ENTRA_CLIENT_ID,
+        sub: ENTRA_CLIENT_ID,
+        jti: uuid::Uuid::new_v4().to_string(),
+        iat: now,
+        nbf: now,
+        exp: now + CLIENT_ASSERTION_LIFETIME_SECS,
+    };
+
+    Ok(jsonwebtoken::encode(&header, &claims, &key)?)
+}
+
+#[cfg(windows)]
+fn dpapi(data: &[u8], protect: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    use std::ptr::{null, null_mut};
+    use winapi::um::{
+        dpapi::{CryptProtectData, CryptUnprotectData, CRYPTPROTECT_UI_FORBIDDEN},
+        winbase::LocalFree,
+        wincrypt::DATA_BLOB,
+    };
+
+    let mut input = data.to_vec();
+    let mut data_in = DATA_BLOB {
+        cbData: input.len() as u32,
+        pbData: input.as_mut_ptr(),
+    };
+    let mut data_out = DATA_BLOB {
+        cbData: 0,
+        pbData: null_mut(),
+    };
+
+    let ok = unsafe {
+        if protect {
+            CryptProtectData(
+                &mut data_in,
+                null(),
+                null_mut(),
+                null_mut(),
+                null_mut(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                &mut data_out,
+            )
+        } else {
+            CryptUnprotectData(
+                &mut data_in,
+                null_mut(),
+                null_mut(),
+                null_mut(),
+                null_mut(),
+                CRYPTPROTECT_UI_FORBIDDEN,
+                &mut data_out,
+            )
+        }
+    };
+
+    if ok == 0 {
+        return Err(format!("DPAPI failed: {}", std::io::Error::last_os_error()).into());
+    }
+
+    let output =
+        unsafe { std::slice::from_raw_parts(data_out.pbData, data_out.cbData as usize) }.to_vec();
+
+    unsafe {
+        LocalFree(data_out.pbData as *mut _);
+    }
+
+    Ok(output)
+}
+
+/// Reads the token persisted by a previous run, it is encrypted with DPAPI for the current account.
+#[cfg(windows)]
+fn load_persisted_token(path: &Path) -> Result<CachedToken, Box<dyn Error>> {
+    let decrypted = dpapi(&fs::read(path)?, false)?;
+
+    Ok(bincode::deserialize(&decrypted)?)
+}
+
+#[cfg(windows)]
+fn persist_token(path: &Path, token: &CachedToken) -> Result<(), Box<dyn Error>> {
+    let encrypted = dpapi(&bincode::serialize(token)?, true)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    Ok(fs::write(path, encrypted)?)
+}
+
+#[cfg(target_os = "linux")]
+fn load_persisted_token(_path: &Path) -> Result<CachedToken, Box<dyn Error>> {
+    Err("Persisting the token cache is not supported on this platform".into())
+}
+
+#[cfg(target_os = "linux")]
+fn persist_token(_path: &Path, _token: &CachedToken) -> Result<(), Box<dyn Error>> {
+    Err("Persisting the token cache is not supported on this platform".into())
+}
+
+/// Client for the Graph API, every request of a run shares the access token.
+/// Requests time out and are retried on throttling and server errors.
+pub struct GraphClient {
+    http: Client,
+    config: EntraConfig,
+    settings: GraphConfig,
+    /// location of the encrypted token between runs, if `persist_token_cache` is enabled
+    token_cache_file: Option<PathBuf>,
+    /// `ENTRA_CLIENT_SECRET` of the build, used with `auth = "secret"`
+    client_secret: Option<String>,
+}
+
+impl GraphClient {
+    pub fn new(
+        config: &EntraConfig,
+        settings: &GraphConfig,
+        execution_context: &ExecutionContext,
+    ) -> Self {
+        let token_cache_file = if config.persist_token_cache {
+            let install_path = match execution_context {
+                ExecutionContext::System => get_system_install_path(),
+                ExecutionContext::User => get_user_install_path(),
+            };
+
+            install_path.ok().map(|p| p.join(TOKEN_CACHE_FILE_NAME))
+        } else {
+            None
+        };
+
+        let http = Client::builder()
+            .timeout(Duration::from_secs(settings.timeout_secs))
+            .build()
+            .unwrap_or_else(|e| {
+                warn!(
+                    "Failed to build the Graph HTTP client, using the default: {}",
+                    e
+                );
+                Client::new()
+            });
+
+        GraphClient {
+            http,
+            config: config.clone(),
+            settings: settings.clone(),
+            token_cache_file,
+            client_secret: ENTRA_CLIENT_SECRET.map(|s| s.to_string()),
+        }
+    }
+
+    /// Uses `secret` instead of the client secret of the build.
+    #[cfg(test)]
+    pub fn with_client_secret(mut self, secret: &str) -> Self {
+        self.client_secret = Some(secret.to_string());
+        self
+    }
+
+    /// Sends the request built by `request`, retrying throttled, failed and timed out requests
+    /// up to `max_attempts` times. The delay is taken from `Retry-After` or doubles with every attempt.
+    fn send(&self, request: impl Fn() -> RequestBuilder) -> Result<Response, GraphError> {
+        let mut attempt = 1;
+
+        loop {
+            let result = request().send();
+
+            let delay = match &result {
+                Ok(response) if is_retryable(response.status()) => {
+                    Some(retry_after(response).unwrap_or(backoff(attempt)))
+                }
+                Err(e) if e.is_timeout() || e.is_connect() => Some(backoff(attempt)),
+                _ => None,
+            };
+
+            match delay {
+                Some(delay) if attempt < self.settings.max_attempts => {
+                    let delay = delay.min(Duration::from_secs(self.settings.max_retry_delay_secs));
+
+                    warn!(
+                        "Graph request failed (attempt {}/{}), retrying in {:?}: {}",
+                        attempt,
+                        self.settings.max_attempts,
+                        delay,
+                        match &result {
+                            Ok(response) => response.status().to_string(),
+                            Err(e) => e.to_string(),
+                        }
+                    );
+
+                    std::thread::sleep(delay);
+                    attempt += 1;
+                }
+                _ => return result.map_err(GraphError::Network),
+            }
+        }
+    }
+
+    fn request_access_token(&self) -> Result<CachedToken, GraphError> {
+        let token_url = format!(
+            "{}/{}/oauth2/v2.0/token",
+            self.config.authority_host(),
+            ENTRA_TENANT_ID
+        );
+
+        let mut form = vec![
+            ("client_id", ENTRA_CLIENT_ID.to_string()),
+            ("scope", format!("{}/.default", self.config.graph_host())),
+            ("grant_type", "client_credentials".to_string()),
+        ];
+
+        match self.config.auth {
+            EntraAuth::Secret => {
+                let secret = self.client_secret.as_ref().ok_or_else(|| {
+                    GraphError::Auth(
+                        "No client secret was set at build time, configure certificate auth".into(),
+                    )
+                })?;
+                form.push(("client_secret", secret.to_string()));
+            }
+            EntraAuth::Certificate => {
+                let (certificate, private_key) =
+                    match (&self.config.certificate, &self.config.private_key) {
+                        (Some(c), Some(k)) => (c, k),
+                        _ => {
+                            return Err(GraphError::Auth(
+                                "Certificate auth requires certificate and private_key".into(),
+                            ))
+                        }
+                    };
+
+                form.push(("client_assertion_type", CLIENT_ASSERTION_TYPE.to_string()));
+                form.push((
+                    "client_assertion",
+                    build_client_assertion(&token_url, certificate, private_key)
+                        .map_err(|e| GraphError::Auth(e.to_string()))?,
+                ));
+            }
+        }
+
+        let response = self.send(|| self.http.post(&token_url).form(&form))?;
+
+        if is_retryable(response.status()) {
+            return Err(GraphError::from_response(response));
+        }
+
+        let token_response: Value = response
+            .json()
+            .map_err(|e| GraphError::Other(format!("Invalid token response: {}", e)))?;
+
+        match token_response["access_token"].as_str() {
+            Some(token) => Ok(CachedToken {
+                client_id: ENTRA_CLIENT_ID.to_string(),
+                audience: self.config.graph_host().to_string(),
+                access_token: token.to_string(),
+                expires_at: unix_now().unwrap_or(0)
+                    + token_response["expires_in"].as_u64().unwrap_or(0),
+            }),
+            None => Err(GraphError::Auth(format!(
+                "Failed to get access token: {}",
+                token_response["error_description"]
+                    .as_str()
+                    .unwrap_or("no error description")
+            ))),
+        }
+    }
+
+    fn access_token(&self) -> Result<String, GraphError> {
+        let mut cache = TOKEN_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        let audience = self.config.graph_host();
+
+        if let Some(token) = cache.as_ref().filter(|t| t.is_valid(audience)) {
+            return Ok(token.access_token.clone());
+        }
+
+        if let Some(path) = self.token_cache_file.as_ref().filter(|p| p.is_file()) {
+            match load_persisted_token(path) {
+                Ok(token) if token.is_valid(audience) => {
+                    info!("Using the persisted access token");
+                    let access_token = token.access_token.clone();
+                    *cache = Some(token);
+                    return Ok(access_token);
+                }
+                Ok(_) => info!("Persisted access token has expired"),
+                Err(e) => warn!("Failed to read the persisted access token: {}", e),
+            }
+        }
+
+        info!("Requesting a new access token");
+        let token = self.request_access_token()?;
+
+        if let Some(path) = &self.token_cache_file {
+            if let Err(e) = persist_token(path, &token) {
+                warn!("Failed to persist the access token: {}", e);
+            }
+        }
+
+        let access_token = token.access_token.clone();
+        *cache = Some(token);
+
+        Ok(access_token)
+    }
+
+    /// Versioned base URL of the Graph API of the configured cloud.
+    pub fn base_url(&self) -> String {
+        format!("{}/{}", self.config.graph_host(), GRAPH_API_VERSION)
+    }
+
+    /// GET request against the Graph API, `url` is absolute (f. e. an `@odata.nextLink`).
+    pub fn get(&self, url: &str) -> Result<Value, GraphError> {
+        let access_token = self.access_token()?;
+
+        let response = self.send(|| self.http.get(url).bearer_auth(&access_token))?;
+
+        if !response.status().is_success() {
+            return Err(GraphError::from_response(response));
+        }
+
+        response
+            .json()
+            .map_err(|e| GraphError::Other(format!("Invalid response: {}", e)))
+    }
+}
+
+/// A local mock of the token endpoint and Graph for the tests of the Graph clients.
+#[cfg(test)]
+pub mod mock {
+    use super::GraphClient;
+    use crate::{config::Config, task::ExecutionContext};
+    use serde_json::{json, Value};
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    /// A local stand-in for the token endpoint and Graph, answering one request per connection.
+    /// `respond` gets the path of a Graph request and the server's base URL and returns the response,
+    /// a body alone is a 200. The paths of the Graph requests are recorded.
+    pub struct MockGraph {
+        base_url: String,
+        requests: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockGraph {
+        pub fn start<R: Into<MockResponse>>(
+            respond: impl Fn(&str, &str) -> R + Send + 'static,
+        ) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+
+            let server_url = base_url.clone();
+            let recorded = requests.clone();
+
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+                    let path = request_line.split(' ').nth(1).unwrap_or("").to_string();
+
+                    let mut content_length = 0;
+                    let mut authorization = None;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        let line = line.trim_end();
+                        if line.is_empty() {
+                            break;
+                        }
+                        let (name, value) = line.split_once(':').unwrap();
+                        match name.to_lowercase().as_str() {
+                            "content-length" => content_length = value.trim().parse().unwrap(),
+                            "authorization" => authorization = Some(value.trim().to_string()),
+                            _ => (),
+                        }
+                    }
+                    let mut body = vec![0; content_length];
+                    reader.read_exact(&mut body).unwrap();
+
+                    let response = if path.contains("/oauth2/v2.0/token") {
+                        let form = String::from_utf8_lossy(&body);
+                        assert!(form.split('&').any(|p| p == "client_secret=mock-secret"));
+                        json!({"access_token": "mock-token", "expires_in": 3600}).into()
+                    } else {
+                        assert_eq!(authorization.as_deref(), Some("Bearer mock-token"));
+                        recorded.lock().unwrap().push(path.clone());
+                        respond(&path, &server_url).into()
+                    };
+
+                    let body = response.body.to_string();
+                    let headers: String = response
+                        .headers
+                        .iter()
+                        .map(|(name, value)| format!("{}: {}\r\n", name, value))
+                        .collect();
+                    write!(
+                        stream,
+                        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+                        response.status,
+                        body.len(),
+                        headers,
+                        body
+                    )
+                    .unwrap();
+                }
+            });
+
+            MockGraph { base_url, requests }
+        }
+
+        /// A client whose token endpoint and Graph are this server, independent of the secret of the build.
+        pub fn client(&self) -> GraphClient {
+            self.client_with("")
+        }
+
+        /// Like `client`, with the given `[graph]` settings.
+        pub fn client_with(&self, graph_settings: &str) -> GraphClient {
+            let config: Config = toml::from_str(&format!(
+                "[entra]\nauthority_host = \"{0}\"\ngraph_host = \"{0}\"\n\n[graph]\n{1}",
+                self.base_url, graph_settings
+            ))
+            .unwrap();
+
+            GraphClient::new(&config.entra, &config.graph, &ExecutionContext::User)
+                .with_client_secret("mock-secret")
+        }
+
+        pub fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    pub struct MockResponse {
+        pub status: u16,
+        pub headers: Vec<(&'static str, String)>,
+        pub body: Value,
+    }
+
+    impl From<Value> for MockResponse {
+        fn from(body: Value) -> Self {
+            MockResponse {
+                status: 200,
+                headers: Vec::new(),
+                body,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{MockGraph, MockResponse};
+    use serde_json::json;
+    use std::{
+        sync::atomic::{AtomicU32, Ordering},
+        time::Instant,
+    };
+
+    /// `failures` responses with `status` and `Retry-After: <retry_after>`, then a 200.
+    fn failing(
+        failures: u32,
+        status: u16,
+        retry_after: Option<u64>,
+    ) -> impl Fn(&str, &str) -> MockResponse {
+        let count = AtomicU32::new(0);
+
+        move |_, _| {
+            if count.fetch_add(1, Ordering::SeqCst) < failures {
+                MockResponse {
+                    status,
+                    headers: retry_after
+                        .map(|s| ("Retry-After", s.to_string()))
+                        .into_iter()
+                        .collect(),
+                    body: json!({"error": {"message": "slow down"}}),
+                }
+            } else {
+                json!({"ok": true}).into()
+            }
+        }
+    }
+
+    #[test]
+    fn retries_after_the_delay_of_retry_after() {
+        let mock = MockGraph::start(failing(1, 429, Some(0)));
+        let client = mock.client();
+
+        let started = Instant::now();
+        let response = client.get(&format!("{}/me", client.base_url())).unwrap();
+
+        assert_eq!(response["ok"], true);
+        assert_eq!(mock.requests().len(), 2);
+        // without Retry-After the first retry waits a second
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mock = MockGraph::start(failing(u32::MAX, 503, None));
+        let client = mock.client_with("max_attempts = 3\nmax_retry_delay_secs = 0\n");
+
+        let result = client.get(&format!("{}/me", client.base_url()));
+
+        assert!(matches!(result, Err(GraphError::Throttled { .. })));
+        assert_eq!(mock.requests().len(), 3);
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        let mock = MockGraph::start(failing(1, 429, Some(3600)));
+        let client = mock.client_with("max_retry_delay_secs = 1\n");
+
+        let started = Instant::now();
+        let response = client.get(&format!("{}/me", client.base_url()));
+
+        assert!(response.is_ok());
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn errors_which_are_not_retryable_are_returned_at_once() {
+        let mock = MockGraph::start(failing(u32::MAX, 404, None));
+        let client = mock.client();
+
+        let result = client.get(&format!("{}/me", client.base_url()));
+
+        assert!(matches!(result, Err(GraphError::NotFound(_))));
+        assert_eq!(mock.requests().len(), 1);
+    }
+
+    #[test]
+    fn backoff_doubles_and_saturates() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(4), Duration::from_secs(8));
+        assert_eq!(backoff(64), Duration::from_secs(1 << 63));
+        assert_eq!(backoff(65), Duration::from_secs(u64::MAX));
+        assert_eq!(backoff(u32::MAX), Duration::from_secs(u64::MAX));
+    }
+}
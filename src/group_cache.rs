@@ -0,0 +1,133 @@
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fmt::Display,
+    fs,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    common::{get_system_install_path, get_user_install_path},
+    group_provider::{GroupMembership, GroupPrincipal},
+    task::ExecutionContext,
+};
+
+const GROUP_CACHE_DIR_NAME: &str = "group_cache";
+
+/// Where the group membership used for a run came from.
+#[derive(Clone, Debug, Default)]
+pub enum MembershipSource {
+    /// looked up during this run
+    Live,
+    /// the lookup failed, the result of an earlier run (unix timestamp) was used
+    Cached { fetched_at: u64 },
+    /// no membership is known, group-restricted tasks are skipped
+    None,
+    /// no task needed a lookup
+    #[default]
+    NotNeeded,
+}
+
+impl Display for MembershipSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MembershipSource::Live => write!(f, "live"),
+            MembershipSource::Cached { fetched_at } => {
+                let age = unix_now().saturating_sub(*fetched_at);
+                write!(f, "cached ({} minutes old)", age / 60)
+            }
+            MembershipSource::None => write!(f, "none"),
+            MembershipSource::NotNeeded => write!(f, "not looked up, no task has a group rule"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct CachedMembership {
+    #[serde(alias = "upn")]
+    principal: String,
+    fetched_at: u64,
+    membership: GroupMembership,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Users are keyed by their UPN, devices by their prefixed device id.
+fn cache_key(principal: &GroupPrincipal) -> String {
+    match principal {
+        GroupPrincipal::User(upn) => upn.to_lowercase(),
+        GroupPrincipal::Device(id) => format!("device:{}", id.to_lowercase()),
+    }
+}
+
+/// One file per user or device, named after the hash of its key.
+fn cache_file(execution_context: &ExecutionContext, key: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let mut path = match execution_context {
+        ExecutionContext::System => get_system_install_path()?,
+        ExecutionContext::User => get_user_install_path()?,
+    };
+
+    path.push(GROUP_CACHE_DIR_NAME);
+    path.push(format!("{}.json", sha256::digest(key)));
+
+    Ok(path)
+}
+
+pub fn store(
+    execution_context: &ExecutionContext,
+    principal: &GroupPrincipal,
+    membership: &GroupMembership,
+) {
+    let key = cache_key(principal);
+    let res = cache_file(execution_context, &key).and_then(|path| {
+        let cached = CachedMembership {
+            principal: key.clone(),
+            fetched_at: unix_now(),
+            membership: membership.clone(),
+        };
+
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, serde_json::to_vec(&cached)?)?;
+
+        Ok(())
+    });
+
+    if let Err(e) = res {
+        warn!(
+            "Failed to cache the group membership of {}: {}",
+            principal, e
+        );
+    }
+}
+
+/// Returns the cached membership of `principal` and when it was fetched, if it is not older than `ttl_hours`.
+pub fn load(
+    execution_context: &ExecutionContext,
+    principal: &GroupPrincipal,
+    ttl_hours: u64,
+) -> Option<(GroupMembership, u64)> {
+    let key = cache_key(principal);
+    let path = cache_file(execution_context, &key).ok()?;
+    let cached: CachedMembership = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+
+    if !cached.principal.eq_ignore_ascii_case(&key) {
+        return None;
+    }
+
+    if unix_now().saturating_sub(cached.fetched_at) > ttl_hours * 3600 {
+        warn!(
+            "Cached group membership of {} is older than {} hours, ignoring it",
+            principal, ttl_hours
+        );
+        return None;
+    }
+
+    Some((cached.membership, cached.fetched_at))
+}
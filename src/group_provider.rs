@@ -0,0 +1,641 @@
+use ldap3::{ldap_escape, LdapConn, LdapConnSettings, LdapError, Scope, SearchEntry};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt::Display,
+    fs,
+    time::Duration,
+};
+
+use crate::{
+    config::{Config, GroupProviderKind, LdapConfig, StaticGroupsConfig},
+    entra_groups::get_entra_groups,
+    graph::{GraphClient, GraphError},
+    task::{ExecutionContext, GroupRef},
+    wildcard,
+};
+
+/// Ids and names of the groups a user or device is a member of, both lowercase.
+/// The ids are Entra object ids, LDAP DNs or SIDs/gids, depending on the provider.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct GroupMembership {
+    pub ids: HashSet<String>,
+    pub names: HashSet<String>,
+}
+
+impl GroupMembership {
+    pub fn insert(&mut self, id: &str, name: Option<&str>) {
+        self.ids.insert(id.to_lowercase());
+
+        if let Some(name) = name {
+            self.names.insert(name.to_lowercase());
+        }
+    }
+
+    pub fn contains(&self, group: &GroupRef) -> bool {
+        match group {
+            GroupRef::Id(id) => self.ids.contains(id),
+            GroupRef::Name(name) => self.names.contains(name),
+        }
+    }
+
+    /// Adds the groups of another provider.
+    pub fn extend(&mut self, other: GroupMembership) {
+        self.ids.extend(other.ids);
+        self.names.extend(other.names);
+    }
+}
+
+/// The directory object whose groups are matched against the group rules:
+/// the user in user context, the device in system context.
+#[derive(Clone, Debug)]
+pub enum GroupPrincipal {
+    User(String),
+    /// Entra device id, not the object id of the device
+    Device(String),
+}
+
+impl Display for GroupPrincipal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GroupPrincipal::User(upn) => write!(f, "user {}", upn),
+            GroupPrincipal::Device(id) => write!(f, "device {}", id),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GroupProviderError {
+    Graph(GraphError),
+    Ldap(LdapError),
+    /// the provider is not configured or its configuration cannot be read
+    Config(String),
+    Other(String),
+}
+
+impl Display for GroupProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GroupProviderError::Graph(e) => write!(f, "{}", e),
+            GroupProviderError::Ldap(e) => write!(f, "LDAP request failed: {}", e),
+            GroupProviderError::Config(reason) => {
+                write!(f, "Invalid group provider configuration: {}", reason)
+            }
+            GroupProviderError::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl Error for GroupProviderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GroupProviderError::Graph(e) => Some(e),
+            GroupProviderError::Ldap(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<LdapError> for GroupProviderError {
+    fn from(e: LdapError) -> Self {
+        GroupProviderError::Ldap(e)
+    }
+}
+
+pub trait GroupProvider {
+    /// Groups of `principal`, a kind of principal the provider does not know has no groups.
+    fn groups_of(&self, principal: &GroupPrincipal) -> Result<GroupMembership, GroupProviderError>;
+}
+
+/// Builds the providers listed in `[groups]`, combined into one.
+pub fn build_group_provider(
+    config: &Config,
+    execution_context: &ExecutionContext,
+) -> Result<CombinedGroupProvider, GroupProviderError> {
+    let mut providers: Vec<Box<dyn GroupProvider>> = Vec::new();
+
+    for kind in &config.groups.providers {
+        providers.push(match kind {
+            GroupProviderKind::Entra => Box::new(EntraGroupProvider {
+                graph: GraphClient::new(&config.entra, &config.graph, execution_context),
+                transitive: config.entra.transitive_membership,
+            }),
+            GroupProviderKind::Ldap => match &config.groups.ldap {
+                Some(ldap) => Box::new(LdapGroupProvider(ldap.clone())),
+                None => {
+                    return Err(GroupProviderError::Config(
+                        "provider ldap requires [groups.ldap]".into(),
+                    ))
+                }
+            },
+            GroupProviderKind::Local => Box::new(LocalGroupProvider()),
+            GroupProviderKind::Static => match &config.groups.static_ {
+                Some(static_) => Box::new(StaticGroupProvider::new(static_)?),
+                None => {
+                    return Err(GroupProviderError::Config(
+                        "provider static requires [groups.static]".into(),
+                    ))
+                }
+            },
+        });
+    }
+
+    Ok(CombinedGroupProvider(providers))
+}
+
+/// Union of the groups of all providers, fails if one of them fails.
+pub struct CombinedGroupProvider(pub Vec<Box<dyn GroupProvider>>);
+
+impl GroupProvider for CombinedGroupProvider {
+    fn groups_of(&self, principal: &GroupPrincipal) -> Result<GroupMembership, GroupProviderError> {
+        let mut groups = GroupMembership::default();
+
+        for provider in &self.0 {
+            groups.extend(provider.groups_of(principal)?);
+        }
+
+        Ok(groups)
+    }
+}
+
+pub struct EntraGroupProvider {
+    graph: GraphClient,
+    transitive: bool,
+}
+
+impl GroupProvider for EntraGroupProvider {
+    fn groups_of(&self, principal: &GroupPrincipal) -> Result<GroupMembership, GroupProviderError> {
+        get_entra_groups(&self.graph, principal, self.transitive).map_err(GroupProviderError::Graph)
+    }
+}
+
+/// Groups of the user entry in an LDAP directory, f. e. an on-premises Active Directory.
+/// The ids are the DNs of the groups, the names their CNs.
+pub struct LdapGroupProvider(pub LdapConfig);
+
+/// The value of the first RDN, if it is a CN, f. e. `Sales` of `CN=Sales,OU=Groups,DC=contoso,DC=com`.
+fn common_name(dn: &str) -> Option<&str> {
+    dn.split(',')
+        .next()
+        .and_then(|rdn| rdn.split_once('='))
+        .filter(|(attribute, _)| attribute.trim().eq_ignore_ascii_case("cn"))
+        .map(|(_, value)| value.trim())
+}
+
+impl GroupProvider for LdapGroupProvider {
+    fn groups_of(&self, principal: &GroupPrincipal) -> Result<GroupMembership, GroupProviderError> {
+        let GroupPrincipal::User(upn) = principal else {
+            info!("Devices are not looked up in LDAP");
+            return Ok(GroupMembership::default());
+        };
+
+        let config = &self.0;
+        let settings =
+            LdapConnSettings::new().set_conn_timeout(Duration::from_secs(config.timeout_secs));
+        let mut ldap = LdapConn::with_settings(settings, &config.url)?;
+
+        if let Some(bind_dn) = &config.bind_dn {
+            let password = match &config.bind_password_file {
+                Some(path) => fs::read_to_string(path).map_err(|e| {
+                    GroupProviderError::Config(format!("{}: {}", path.display(), e))
+                })?,
+                None => String::new(),
+            };
+
+            ldap.simple_bind(bind_dn, password.trim_end())?.success()?;
+        }
+
+        let filter = config
+            .user_filter
+            .replace("{upn}", &ldap_escape(upn.as_str()));
+        let (entries, _) = ldap
+            .search(&config.base_dn, Scope::Subtree, &filter, vec!["memberOf"])?
+            .success()?;
+
+        let user = match entries.into_iter().next() {
+            Some(entry) => SearchEntry::construct(entry),
+            None => {
+                return Err(GroupProviderError::Other(format!(
+                    "user {} not found in LDAP",
+                    upn
+                )))
+            }
+        };
+
+        let mut groups = GroupMembership::default();
+
+        if config.transitive {
+            // LDAP_MATCHING_RULE_IN_CHAIN resolves nested groups on the server
+            let filter = format!(
+                "(&(objectClass=group)(member:1.2.840.113556.1.4.1941:={}))",
+                ldap_escape(user.dn.as_str())
+            );
+            let (entries, _) = ldap
+                .search(&config.base_dn, Scope::Subtree, &filter, vec!["cn"])?
+                .success()?;
+
+            for entry in entries {
+                let group = SearchEntry::construct(entry);
+                groups.insert(&group.dn, common_name(&group.dn));
+            }
+        } else {
+            let member_of = user
+                .attrs
+                .iter()
+                .find(|(attribute, _)| attribute.eq_ignore_ascii_case("memberOf"))
+                .map(|(_, dns)| dns.as_slice())
+                .unwrap_or_default();
+
+            for dn in member_of {
+                groups.insert(dn, common_name(dn));
+            }
+        }
+
+        ldap.unbind().ok();
+
+        Ok(groups)
+    }
+}
+
+/// Groups of the account the process is running as: the token groups on Windows (ids are SIDs),
+/// the primary and supplementary groups on Linux (ids are gids). Devices have no local groups.
+pub struct LocalGroupProvider();
+
+impl GroupProvider for LocalGroupProvider {
+    fn groups_of(&self, principal: &GroupPrincipal) -> Result<GroupMembership, GroupProviderError> {
+        match principal {
+            GroupPrincipal::User(_) => local_groups(),
+            GroupPrincipal::Device(_) => Ok(GroupMembership::default()),
+        }
+    }
+}
+
+#[cfg(windows)]
+fn local_groups() -> Result<GroupMembership, GroupProviderError> {
+    use std::ptr::null_mut;
+    use winapi::{
+        shared::sddl::ConvertSidToStringSidW,
+        um::{
+            handleapi::CloseHandle,
+            processthreadsapi::{GetCurrentProcess, OpenProcessToken},
+            securitybaseapi::GetTokenInformation,
+            winbase::{LocalFree, LookupAccountSidW},
+            winnt::{TokenGroups, HANDLE, SID_NAME_USE, TOKEN_GROUPS, TOKEN_QUERY},
+        },
+    };
+
+    let mut groups = GroupMembership::default();
+
+    unsafe {
+        let mut token: HANDLE = null_mut();
+
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            return Err(GroupProviderError::Other(
+                "Failed to open the process token".into(),
+            ));
+        }
+
+        let mut len: u32 = 0;
+        GetTokenInformation(token, TokenGroups, null_mut(), 0, &mut len);
+
+        // u64 keeps the buffer aligned for TOKEN_GROUPS
+        let mut buf: Vec<u64> = vec![0; (len as usize).div_ceil(8)];
+        let ok = GetTokenInformation(
+            token,
+            TokenGroups,
+            buf.as_mut_ptr() as *mut _,
+            len,
+            &mut len,
+        );
+        CloseHandle(token);
+
+        if ok == 0 {
+            return Err(GroupProviderError::Other(
+                "Failed to read the token groups".into(),
+            ));
+        }
+
+        let token_groups = &*(buf.as_ptr() as *const TOKEN_GROUPS);
+        let entries = std::slice::from_raw_parts(
+            token_groups.Groups.as_ptr(),
+            token_groups.GroupCount as usize,
+        );
+
+        for entry in entries {
+            let mut sid_str = null_mut();
+            if ConvertSidToStringSidW(entry.Sid, &mut sid_str) == 0 {
+                continue;
+            }
+
+            let mut sid_len = 0;
+            while *sid_str.add(sid_len) != 0 {
+                sid_len += 1;
+            }
+
+            let sid = String::from_utf16_lossy(std::slice::from_raw_parts(sid_str, sid_len));
+            LocalFree(sid_str as *mut _);
+
+            let mut name = [0u16; 256];
+            let mut name_len = name.len() as u32;
+            let mut domain = [0u16; 256];
+            let mut domain_len = domain.len() as u32;
+            let mut sid_use: SID_NAME_USE = 0;
+
+            let name = if LookupAccountSidW(
+                null_mut(),
+                entry.Sid,
+                name.as_mut_ptr(),
+                &mut name_len,
+                domain.as_mut_ptr(),
+                &mut domain_len,
+                &mut sid_use,
+            ) != 0
+            {
+                Some(String::from_utf16_lossy(&name[..name_len as usize]))
+            } else {
+                None
+            };
+
+            groups.insert(&sid, name.as_deref());
+        }
+    }
+
+    Ok(groups)
+}
+
+#[cfg(target_os = "linux")]
+fn local_groups() -> Result<GroupMembership, GroupProviderError> {
+    use std::ffi::CStr;
+
+    let mut gids: Vec<libc::gid_t> = unsafe {
+        let count = libc::getgroups(0, std::ptr::null_mut());
+        if count < 0 {
+            return Err(GroupProviderError::Other(
+                "Failed to read the supplementary groups".into(),
+            ));
+        }
+
+        let mut gids = vec![0; count as usize];
+        let count = libc::getgroups(count, gids.as_mut_ptr());
+        gids.truncate(count.max(0) as usize);
+        gids
+    };
+    gids.push(unsafe { libc::getegid() });
+    gids.sort();
+    gids.dedup();
+
+    let mut groups = GroupMembership::default();
+
+    for gid in gids {
+        let mut buf: Vec<libc::c_char> = vec![0; 16384];
+        let mut grp: libc::group = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::group = std::ptr::null_mut();
+
+        let rc =
+            unsafe { libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+
+        let name = if rc == 0 && !result.is_null() {
+            Some(
+                unsafe { CStr::from_ptr(grp.gr_name) }
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        } else {
+            None
+        };
+
+        groups.insert(&gid.to_string(), name.as_deref());
+    }
+
+    Ok(groups)
+}
+
+/// Contents of the static mapping file: UPN or device id patterns -> group names.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StaticGroupsFile {
+    users: HashMap<String, HashSet<String>>,
+    devices: HashMap<String, HashSet<String>>,
+}
+
+/// Groups from a mapping file, f. e. for machines without a directory.
+/// The keys are patterns (see `wildcard::matches`), every group is matched by id and by name.
+pub struct StaticGroupProvider(StaticGroupsFile);
+
+impl StaticGroupProvider {
+    pub fn new(config: &StaticGroupsConfig) -> Result<Self, GroupProviderError> {
+        let content = fs::read_to_string(&config.file)
+            .map_err(|e| GroupProviderError::Config(format!("{}: {}", config.file.display(), e)))?;
+
+        toml::from_str(&content)
+            .map(StaticGroupProvider)
+            .map_err(|e| GroupProviderError::Config(format!("{}: {}", config.file.display(), e)))
+    }
+}
+
+impl GroupProvider for StaticGroupProvider {
+    fn groups_of(&self, principal: &GroupPrincipal) -> Result<GroupMembership, GroupProviderError> {
+        let (mapping, key) = match principal {
+            GroupPrincipal::User(upn) => (&self.0.users, upn),
+            GroupPrincipal::Device(id) => (&self.0.devices, id),
+        };
+
+        let mut groups = GroupMembership::default();
+
+        for (pattern, names) in mapping {
+            if wildcard::matches(pattern, key) {
+                for name in names {
+                    groups.insert(name, Some(name));
+                }
+            }
+        }
+
+        Ok(groups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, env, path::PathBuf, rc::Rc};
+
+    /// Writes the static mapping file to the temp directory.
+    fn static_provider(name: &str, content: &str) -> StaticGroupProvider {
+        let file = env::temp_dir().join(format!(
+            "repo_task_run_test_{}_{}.toml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&file, content).unwrap();
+
+        let provider = StaticGroupProvider::new(&StaticGroupsConfig { file: file.clone() });
+        fs::remove_file(file).ok();
+
+        provider.unwrap()
+    }
+
+    fn user(upn: &str) -> GroupPrincipal {
+        GroupPrincipal::User(upn.into())
+    }
+
+    /// Fails and counts its calls in the shared counter.
+    struct FailingProvider(Rc<Cell<u32>>);
+
+    impl GroupProvider for FailingProvider {
+        fn groups_of(&self, _: &GroupPrincipal) -> Result<GroupMembership, GroupProviderError> {
+            self.0.set(self.0.get() + 1);
+            Err(GroupProviderError::Other("unavailable".into()))
+        }
+    }
+
+    #[test]
+    fn static_patterns_match_users_and_devices() {
+        let provider = static_provider(
+            "static_patterns",
+            r#"
+[users]
+"*@contoso.com" = ["Staff"]
+"jdoe@*" = ["Sales"]
+
+[devices]
+"1a2b*" = ["Kiosks"]
+"#,
+        );
+
+        let groups = provider.groups_of(&user("JDoe@Contoso.com")).unwrap();
+        assert_eq!(
+            groups.names,
+            HashSet::from(["staff".into(), "sales".into()])
+        );
+        assert!(groups.contains(&GroupRef::Id("staff".into())));
+
+        let groups = provider.groups_of(&user("jane@fabrikam.com")).unwrap();
+        assert!(groups.ids.is_empty());
+
+        let groups = provider
+            .groups_of(&GroupPrincipal::Device("1A2B3C".into()))
+            .unwrap();
+        assert_eq!(groups.names, HashSet::from(["kiosks".into()]));
+    }
+
+    #[test]
+    fn static_file_errors_are_config_errors() {
+        let missing = StaticGroupProvider::new(&StaticGroupsConfig {
+            file: PathBuf::from("/nonexistent/repo_task_run_groups.toml"),
+        });
+        assert!(matches!(missing, Err(GroupProviderError::Config(_))));
+
+        let file = env::temp_dir().join(format!(
+            "repo_task_run_test_static_invalid_{}.toml",
+            std::process::id()
+        ));
+        fs::write(&file, "groups = 1").unwrap();
+        let invalid = StaticGroupProvider::new(&StaticGroupsConfig { file: file.clone() });
+        fs::remove_file(file).ok();
+        assert!(matches!(invalid, Err(GroupProviderError::Config(_))));
+    }
+
+    #[test]
+    fn combined_is_the_union() {
+        let combined = CombinedGroupProvider(vec![
+            Box::new(static_provider(
+                "combined_a",
+                "[users]\n\"*\" = [\"staff\"]\n",
+            )),
+            Box::new(static_provider(
+                "combined_b",
+                "[users]\n\"jdoe@*\" = [\"sales\"]\n",
+            )),
+        ]);
+
+        let groups = combined.groups_of(&user("jdoe@contoso.com")).unwrap();
+        assert_eq!(
+            groups.names,
+            HashSet::from(["staff".into(), "sales".into()])
+        );
+        assert!(CombinedGroupProvider(Vec::new())
+            .groups_of(&user("jdoe@contoso.com"))
+            .unwrap()
+            .ids
+            .is_empty());
+    }
+
+    #[test]
+    fn combined_fails_on_the_first_failure() {
+        let calls = Rc::new(Cell::new(0));
+        let combined = CombinedGroupProvider(vec![
+            Box::new(static_provider(
+                "combined_fail",
+                "[users]\n\"*\" = [\"staff\"]\n",
+            )),
+            Box::new(FailingProvider(calls.clone())),
+            Box::new(FailingProvider(calls.clone())),
+        ]);
+
+        assert!(matches!(
+            combined.groups_of(&user("jdoe@contoso.com")),
+            Err(GroupProviderError::Other(_))
+        ));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn common_names_of_dns() {
+        assert_eq!(
+            common_name("CN=Sales,OU=Groups,DC=contoso,DC=com"),
+            Some("Sales")
+        );
+        assert_eq!(common_name("cn = IT , OU=Groups"), Some("IT"));
+        assert_eq!(common_name("OU=Groups,DC=contoso,DC=com"), None);
+        assert_eq!(common_name("Sales"), None);
+    }
+
+    #[test]
+    fn providers_without_their_section_are_config_errors() {
+        for (provider, section) in [("ldap", "[groups.ldap]"), ("static", "[groups.static]")] {
+            let config: Config =
+                toml::from_str(&format!("[groups]\nproviders = [\"{}\"]\n", provider)).unwrap();
+
+            match build_group_provider(&config, &ExecutionContext::User) {
+                Err(GroupProviderError::Config(reason)) => assert!(reason.contains(section)),
+                Err(e) => panic!("{}", e),
+                Ok(_) => panic!("{} without {} was accepted", provider, section),
+            }
+        }
+    }
+
+    /// Runs against the LDAP server at `REPO_TASK_RUN_TEST_LDAP_URL`, f. e. a local OpenLDAP or
+    /// Samba AD. `..._BASE_DN`, `..._UPN` and the expected group CN `..._GROUP` are required;
+    /// `..._USER_FILTER`, `..._BIND_DN` and `..._BIND_PASSWORD_FILE` are optional.
+    #[test]
+    #[ignore = "needs a local LDAP server, see REPO_TASK_RUN_TEST_LDAP_URL"]
+    fn ldap_groups_of_a_user() {
+        let var = |name: &str| env::var(format!("REPO_TASK_RUN_TEST_LDAP_{}", name)).ok();
+        let required = |name: &str| var(name).unwrap_or_else(|| panic!("{} is not set", name));
+
+        let provider = LdapGroupProvider(LdapConfig {
+            url: required("URL"),
+            bind_dn: var("BIND_DN"),
+            bind_password_file: var("BIND_PASSWORD_FILE").map(PathBuf::from),
+            base_dn: required("BASE_DN"),
+            user_filter: var("USER_FILTER").unwrap_or_else(|| "(userPrincipalName={upn})".into()),
+            transitive: false,
+            timeout_secs: 10,
+        });
+
+        let groups = provider.groups_of(&user(&required("UPN"))).unwrap();
+        assert!(groups.contains(&GroupRef::Name(required("GROUP").to_lowercase())));
+
+        assert!(provider
+            .groups_of(&GroupPrincipal::Device("1a2b".into()))
+            .unwrap()
+            .ids
+            .is_empty());
+        assert!(matches!(
+            provider.groups_of(&user("nobody@invalid.example")),
+            Err(GroupProviderError::Other(_))
+        ));
+    }
+}
@@ -0,0 +1,334 @@
+use log::{info, warn};
+use std::{collections::HashMap, error::Error, fmt::Display};
+
+use crate::{config::IdentityConfig, task::ExecutionContext};
+
+/// The account the process is running as.
+#[allow(unused)]
+#[derive(Clone, Debug, Default)]
+pub struct Identity {
+    /// account name without the domain, f. e. `jdoe`
+    pub username: String,
+    /// user principal name, f. e. `jdoe@contoso.com`
+    pub upn: Option<String>,
+    /// SID on Windows, uid on Linux
+    pub id: Option<String>,
+    pub display_name: Option<String>,
+    pub domain: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum IdentityError {
+    LookupFailed(String),
+}
+
+impl Display for IdentityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentityError::LookupFailed(reason) => {
+                write!(f, "Failed to look up the current identity: {}", reason)
+            }
+        }
+    }
+}
+
+impl Error for IdentityError {}
+
+pub trait IdentityProvider {
+    fn current_identity(&self) -> Result<Identity, IdentityError>;
+}
+
+/// Looks up the identity of the process token (Windows) or the effective uid (Linux).
+pub struct NativeIdentityProvider();
+
+#[cfg(windows)]
+fn get_user_name_ex(
+    format: windows::Win32::Security::Authentication::Identity::EXTENDED_NAME_FORMAT,
+) -> Option<String> {
+    use windows::{core::PWSTR, Win32::Security::Authentication::Identity::GetUserNameExW};
+
+    let mut len: u32 = 0;
+
+    // the first call fails with ERROR_MORE_DATA and returns the required buffer size
+    unsafe {
+        GetUserNameExW(format, PWSTR::null(), &mut len);
+    }
+
+    if len == 0 {
+        return None;
+    }
+
+    let mut buf: Vec<u16> = vec![0; len as usize];
+
+    if unsafe { GetUserNameExW(format, PWSTR::from_raw(buf.as_mut_ptr()), &mut len) }.0 == 0 {
+        return None;
+    }
+
+    let name = String::from_utf16_lossy(&buf[..len as usize]);
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Returns the SID of the user the process token belongs to, f. e. `S-1-5-18` for SYSTEM.
+#[cfg(windows)]
+pub fn get_process_token_sid() -> Option<String> {
+    use std::ptr::null_mut;
+    use winapi::{
+        shared::sddl::ConvertSidToStringSidW,
+        um::{
+            handleapi::CloseHandle,
+            processthreadsapi::{GetCurrentProcess, OpenProcessToken},
+            securitybaseapi::GetTokenInformation,
+            winbase::LocalFree,
+            winnt::{TokenUser, HANDLE, TOKEN_QUERY, TOKEN_USER},
+        },
+    };
+
+    unsafe {
+        let mut token: HANDLE = null_mut();
+
+        if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            return None;
+        }
+
+        let mut len: u32 = 0;
+        GetTokenInformation(token, TokenUser, null_mut(), 0, &mut len);
+
+        // u64 keeps the buffer aligned for TOKEN_USER
+        let mut buf: Vec<u64> = vec![0; (len as usize).div_ceil(8)];
+        let ok = GetTokenInformation(token, TokenUser, buf.as_mut_ptr() as *mut _, len, &mut len);
+        CloseHandle(token);
+
+        if ok == 0 {
+            return None;
+        }
+
+        let token_user = &*(buf.as_ptr() as *const TOKEN_USER);
+
+        let mut sid_str = null_mut();
+        if ConvertSidToStringSidW(token_user.User.Sid, &mut sid_str) == 0 {
+            return None;
+        }
+
+        let mut sid_len = 0;
+        while *sid_str.add(sid_len) != 0 {
+            sid_len += 1;
+        }
+
+        let sid = String::from_utf16_lossy(std::slice::from_raw_parts(sid_str, sid_len));
+        LocalFree(sid_str as *mut _);
+
+        Some(sid)
+    }
+}
+
+#[cfg(windows)]
+impl IdentityProvider for NativeIdentityProvider {
+    fn current_identity(&self) -> Result<Identity, IdentityError> {
+        use windows::Win32::Security::Authentication::Identity::{
+            NameDisplay, NameSamCompatible, NameUserPrincipal,
+        };
+
+        // DOMAIN\username
+        let (domain, username) = match get_user_name_ex(NameSamCompatible) {
+            Some(sam) => match sam.split_once('\\') {
+                Some((domain, username)) => (Some(domain.to_string()), username.to_string()),
+                None => (None, sam),
+            },
+            None => match std::env::var("USERNAME") {
+                Ok(username) => (std::env::var("USERDOMAIN").ok(), username),
+                Err(e) => {
+                    return Err(IdentityError::LookupFailed(format!(
+                        "neither GetUserNameExW nor %USERNAME% are available: {}",
+                        e
+                    )))
+                }
+            },
+        };
+
+        Ok(Identity {
+            username,
+            upn: get_user_name_ex(NameUserPrincipal),
+            id: get_process_token_sid(),
+            display_name: get_user_name_ex(NameDisplay),
+            domain,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl IdentityProvider for NativeIdentityProvider {
+    fn current_identity(&self) -> Result<Identity, IdentityError> {
+        use std::ffi::CStr;
+
+        let uid = unsafe { libc::geteuid() };
+
+        let mut buf: Vec<libc::c_char> = vec![0; 16384];
+        let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+
+        let rc =
+            unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+
+        if rc != 0 || result.is_null() {
+            return Err(IdentityError::LookupFailed(format!(
+                "no passwd entry for uid {} (error {})",
+                uid, rc
+            )));
+        }
+
+        let username = unsafe { CStr::from_ptr(pwd.pw_name) }
+            .to_string_lossy()
+            .into_owned();
+
+        // the first field of the GECOS entry is the full name
+        let display_name = if pwd.pw_gecos.is_null() {
+            None
+        } else {
+            unsafe { CStr::from_ptr(pwd.pw_gecos) }
+                .to_string_lossy()
+                .split(',')
+                .next()
+                .filter(|n| !n.is_empty())
+                .map(|n| n.to_string())
+        };
+
+        // directory users (sssd, himmelblau) are usually named like their UPN
+        let (upn, domain) = match username.split_once('@') {
+            Some((_, domain)) => (Some(username.clone()), Some(domain.to_string())),
+            None => (None, None),
+        };
+
+        Ok(Identity {
+            username,
+            upn,
+            id: Some(uid.to_string()),
+            display_name,
+            domain,
+        })
+    }
+}
+
+/// Completes the identity of another provider with a UPN from the configuration,
+/// if the provider could not resolve one. Only in user context, the system context runs as
+/// SYSTEM or root, which has no UPN.
+pub struct MappedIdentityProvider<P: IdentityProvider> {
+    inner: P,
+    execution_context: ExecutionContext,
+    upn_mapping: HashMap<String, String>,
+    upn_suffix: Option<String>,
+}
+
+impl<P: IdentityProvider> MappedIdentityProvider<P> {
+    pub fn new(inner: P, config: &IdentityConfig, execution_context: &ExecutionContext) -> Self {
+        MappedIdentityProvider {
+            inner,
+            execution_context: execution_context.clone(),
+            upn_mapping: config
+                .upn_mapping
+                .iter()
+                .map(|(user, upn)| (user.to_lowercase(), upn.clone()))
+                .collect(),
+            upn_suffix: config.upn_suffix.clone(),
+        }
+    }
+}
+
+impl<P: IdentityProvider> IdentityProvider for MappedIdentityProvider<P> {
+    fn current_identity(&self) -> Result<Identity, IdentityError> {
+        let mut identity = self.inner.current_identity()?;
+
+        if identity.upn.is_some() || self.execution_context == ExecutionContext::System {
+            return Ok(identity);
+        }
+
+        if let Some(upn) = self.upn_mapping.get(&identity.username.to_lowercase()) {
+            info!("Using mapped UPN {} for {}", upn, identity.username);
+            identity.upn = Some(upn.clone());
+        } else if let Some(suffix) = &self.upn_suffix {
+            let upn = format!("{}@{}", identity.username, suffix);
+            info!("Using UPN {} derived from the configured suffix", upn);
+            identity.upn = Some(upn);
+        } else {
+            warn!(
+                "No UPN for {}, group rules will not match",
+                identity.username
+            );
+        }
+
+        Ok(identity)
+    }
+}
+
+/// Returns a fixed identity, for tests and troubleshooting.
+#[allow(unused)]
+pub struct StaticIdentityProvider(pub Identity);
+
+impl IdentityProvider for StaticIdentityProvider {
+    fn current_identity(&self) -> Result<Identity, IdentityError> {
+        Ok(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provider(
+        upn: Option<&str>,
+        execution_context: ExecutionContext,
+    ) -> MappedIdentityProvider<StaticIdentityProvider> {
+        let config = IdentityConfig {
+            upn_mapping: HashMap::from([("JDoe".to_string(), "john.doe@contoso.com".to_string())]),
+            upn_suffix: Some("contoso.com".to_string()),
+        };
+        let identity = Identity {
+            username: "jdoe".to_string(),
+            upn: upn.map(|u| u.to_string()),
+            ..Default::default()
+        };
+
+        MappedIdentityProvider::new(
+            StaticIdentityProvider(identity),
+            &config,
+            &execution_context,
+        )
+    }
+
+    fn upn_of(provider: &dyn IdentityProvider) -> Option<String> {
+        provider.current_identity().unwrap().upn
+    }
+
+    #[test]
+    fn native_upn_takes_precedence() {
+        let provider = provider(Some("jdoe@fabrikam.com"), ExecutionContext::User);
+
+        assert_eq!(upn_of(&provider).as_deref(), Some("jdoe@fabrikam.com"));
+    }
+
+    #[test]
+    fn mapping_is_case_insensitive() {
+        let provider = provider(None, ExecutionContext::User);
+
+        assert_eq!(upn_of(&provider).as_deref(), Some("john.doe@contoso.com"));
+    }
+
+    #[test]
+    fn suffix_is_used_without_mapping() {
+        let mut provider = provider(None, ExecutionContext::User);
+        provider.upn_mapping.clear();
+
+        assert_eq!(upn_of(&provider).as_deref(), Some("jdoe@contoso.com"));
+    }
+
+    #[test]
+    fn no_upn_is_made_up_in_system_context() {
+        let provider = provider(None, ExecutionContext::System);
+
+        assert_eq!(upn_of(&provider), None);
+    }
+}
@@ -1,10 +1,15 @@
 use log::{error, info};
 use std::error::Error;
 use std::fs;
+#[cfg(target_os = "linux")]
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+#[cfg(windows)]
 use windows::Win32::Storage::FileSystem::MOVEFILE_DELAY_UNTIL_REBOOT;
+#[cfg(windows)]
 use windows::{core::PCWSTR, Win32::Storage::FileSystem::MoveFileExW};
+#[cfg(windows)]
 use windows_registry::CURRENT_USER;
 
 use crate::common::*;
@@ -16,6 +21,7 @@ pub trait AutostartConfiguration {
 
 pub struct PerUserAutostart();
 
+#[cfg(windows)]
 impl AutostartConfiguration for PerUserAutostart {
     fn uninstall() -> Result<(), Box<dyn Error>> {
         let mut winadm_path = get_user_install_path()?;
@@ -76,6 +82,7 @@ impl AutostartConfiguration for PerUserAutostart {
 
 pub struct SystemAutostart();
 
+#[cfg(windows)]
 impl AutostartConfiguration for SystemAutostart {
     fn uninstall() -> Result<(), Box<dyn Error>> {
         let mut install_path = get_system_install_path()?;
@@ -163,3 +170,200 @@ impl AutostartConfiguration for SystemAutostart {
         Ok(())
     }
 }
+
+#[cfg(target_os = "linux")]
+const SYSTEMD_UNIT_NAME: &str = "repo_task_run.service";
+
+#[cfg(target_os = "linux")]
+fn systemctl(user: bool, args: &[&str]) -> Result<(), Box<dyn Error>> {
+    let mut cmd = Command::new("systemctl");
+
+    if user {
+        cmd.arg("--user");
+    }
+
+    let output = cmd.args(args).output()?;
+
+    if !output.status.success() {
+        error!(
+            "systemctl {:?} failed ({}): {}",
+            args,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        return Err(format!("systemctl {:?} failed: {}", args, output.status).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn systemd_unit(description: &str, executable: &Path, wanted_by: &str) -> String {
+    format!(
+        r#"[Unit]
+Description={}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+ExecStart="{}"
+
+[Install]
+WantedBy={}
+"#,
+        description,
+        executable.display(),
+        wanted_by
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn copy_own_executable(install_path: &mut PathBuf) -> Result<(), Box<dyn Error>> {
+    if !install_path.exists() {
+        info!(
+            "{} does not exist, creating directory...",
+            install_path.display()
+        );
+        fs::create_dir_all(&*install_path)?;
+    }
+
+    let own_path = std::env::current_exe()?;
+
+    install_path.push(own_path.as_path().file_name().unwrap());
+
+    if own_path != *install_path {
+        info!(
+            "Copying {} to {}",
+            own_path.display(),
+            install_path.display()
+        );
+
+        fs::copy(&own_path, &*install_path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn get_user_unit_path() -> Result<PathBuf, Box<dyn Error>> {
+    let mut path = get_xdg_config_home()?;
+
+    path.push("systemd");
+    path.push("user");
+    path.push(SYSTEMD_UNIT_NAME);
+
+    Ok(path)
+}
+
+#[cfg(target_os = "linux")]
+fn get_system_unit_path() -> PathBuf {
+    let mut path = PathBuf::from("/etc/systemd/system");
+
+    path.push(SYSTEMD_UNIT_NAME);
+
+    path
+}
+
+#[cfg(target_os = "linux")]
+impl AutostartConfiguration for PerUserAutostart {
+    fn uninstall() -> Result<(), Box<dyn Error>> {
+        systemctl(true, &["disable", SYSTEMD_UNIT_NAME]).ok();
+
+        let unit_path = get_user_unit_path()?;
+        if unit_path.exists() {
+            info!("Removing {} ...", unit_path.display());
+            fs::remove_file(&unit_path)?;
+        }
+
+        systemctl(true, &["daemon-reload"]).ok();
+
+        let mut install_path = get_user_install_path()?;
+        let own_path = std::env::current_exe()?;
+
+        install_path.push(own_path.as_path().file_name().unwrap());
+
+        if let Err(e) = fs::remove_file(&install_path) {
+            error!("Failed to remove {}: {:?}", install_path.display(), e);
+        }
+
+        Ok(())
+    }
+
+    fn install() -> Result<(), Box<dyn Error>> {
+        let mut install_path = get_user_install_path()?;
+
+        copy_own_executable(&mut install_path)?;
+
+        let unit_path = get_user_unit_path()?;
+        fs::create_dir_all(unit_path.parent().unwrap())?;
+
+        info!("Writing {}", unit_path.display());
+        fs::write(
+            &unit_path,
+            systemd_unit(
+                &format!("{} (user context)", APP_NAME),
+                &install_path,
+                "default.target",
+            ),
+        )?;
+
+        systemctl(true, &["daemon-reload"])?;
+        systemctl(true, &["enable", SYSTEMD_UNIT_NAME])?;
+
+        info!("User unit enabled");
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl AutostartConfiguration for SystemAutostart {
+    fn uninstall() -> Result<(), Box<dyn Error>> {
+        systemctl(false, &["disable", SYSTEMD_UNIT_NAME]).ok();
+
+        let unit_path = get_system_unit_path();
+        if unit_path.exists() {
+            info!("Removing {} ...", unit_path.display());
+            fs::remove_file(&unit_path)?;
+        }
+
+        systemctl(false, &["daemon-reload"]).ok();
+
+        let mut install_path = get_system_install_path()?;
+        let own_path = std::env::current_exe()?;
+
+        install_path.push(own_path.as_path().file_name().unwrap());
+
+        if install_path.exists() {
+            info!("Removing {} ...", install_path.display());
+            fs::remove_file(&install_path)?;
+        }
+
+        Ok(())
+    }
+
+    fn install() -> Result<(), Box<dyn Error>> {
+        info!("Installing to system");
+        let mut install_path = get_system_install_path()?;
+
+        copy_own_executable(&mut install_path)?;
+
+        let unit_path = get_system_unit_path();
+
+        info!("Writing {}", unit_path.display());
+        fs::write(
+            &unit_path,
+            systemd_unit(
+                &format!("{} (system context)", APP_NAME),
+                &install_path,
+                "multi-user.target",
+            ),
+        )?;
+
+        systemctl(false, &["daemon-reload"])?;
+        systemctl(false, &["enable", SYSTEMD_UNIT_NAME])?;
+
+        info!("System unit enabled");
+        Ok(())
+    }
+}
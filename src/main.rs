@@ -1,87 +1,337 @@
-use std::{env, error::Error};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
 
-use common::{get_appdata_local, get_programdata};
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use cli::{
+    Cli, Command, EXIT_CONFIG_ERROR, EXIT_GROUP_RESOLUTION_FAILED, EXIT_INSTALL_ERROR,
+    EXIT_REPOSITORY_ERROR, EXIT_SUCCESS, EXIT_TASK_FAILED, EXIT_VALIDATION_FAILED,
+};
+use common::{get_system_log_path, get_user_log_path};
+use config::Config;
+use execution_context::{detect_context, TokenContextDetector};
+use group_provider::GroupProviderError;
+use identity::{Identity, IdentityProvider, MappedIdentityProvider, NativeIdentityProvider};
 use installation::{AutostartConfiguration, PerUserAutostart, SystemAutostart};
-use log::{error, info};
+use log::{error, info, warn};
 use task::ExecutionContext;
+use task_fetcher::{TaskFetcher, TaskFetchterError};
 use task_runner::TaskRunner;
 use tracing_appender::rolling::{RollingFileAppender, Rotation};
 
+mod cli;
 mod common;
+mod config;
+mod device;
 mod entra_groups;
+mod execution_context;
 mod gix_repository;
+mod graph;
+mod group_cache;
+mod group_provider;
+mod identity;
 mod installation;
+mod manifest;
+mod rule;
 mod task;
 mod task_fetcher;
 mod task_runner;
+mod wildcard;
 
-fn main() -> Result<(), Box<dyn Error>> {
-    unsafe {
-        winapi::um::wincon::FreeConsole();
+fn resolve_identity(config: &Config, execution_context: &ExecutionContext) -> Option<Identity> {
+    let identity_provider = MappedIdentityProvider::new(
+        NativeIdentityProvider(),
+        &config.identity,
+        execution_context,
+    );
+
+    match identity_provider.current_identity() {
+        Ok(identity) => {
+            info!("Identity: {:?}", identity);
+            Some(identity)
+        }
+        Err(e) => {
+            error!("{}", e);
+            None
+        }
+    }
+}
+
+/// Exit code for errors of fetching the tasks.
+fn fetch_error_exit_code(e: &(dyn Error + 'static)) -> u8 {
+    if let Some(GroupProviderError::Config(_)) = e.downcast_ref::<GroupProviderError>() {
+        return EXIT_CONFIG_ERROR;
+    }
+
+    match e.downcast_ref::<TaskFetchterError>() {
+        Some(
+            TaskFetchterError::GroupResolutionFailed(_)
+            | TaskFetchterError::AttributeResolutionFailed(_),
+        ) => EXIT_GROUP_RESOLUTION_FAILED,
+        _ => EXIT_REPOSITORY_ERROR,
+    }
+}
+
+fn run(execution_context: ExecutionContext, repo_path: &Path, config: &Config) -> u8 {
+    info!("Running tasks...");
+
+    let identity = resolve_identity(config, &execution_context);
+
+    let mut runner = match TaskRunner::new(execution_context, repo_path, identity.as_ref(), config)
+    {
+        Ok(r) => r,
+        Err(e) => {
+            error!("Failed to fetch tasks: {}", e);
+            return fetch_error_exit_code(e.as_ref());
+        }
+    };
+
+    if runner.run() {
+        EXIT_SUCCESS
+    } else {
+        EXIT_TASK_FAILED
+    }
+}
+
+fn install(execution_context: ExecutionContext) -> u8 {
+    let res = match execution_context {
+        ExecutionContext::System => SystemAutostart::install(),
+        ExecutionContext::User => PerUserAutostart::install(),
+    };
+
+    match res {
+        Ok(()) => EXIT_SUCCESS,
+        Err(e) => {
+            error!(
+                "Error installing {:?} autostart: {:?}",
+                execution_context, e
+            );
+            eprintln!("Error installing {:?} autostart: {}", execution_context, e);
+            EXIT_INSTALL_ERROR
+        }
+    }
+}
+
+fn uninstall(execution_context: ExecutionContext) -> u8 {
+    let res = match execution_context {
+        ExecutionContext::System => SystemAutostart::uninstall(),
+        ExecutionContext::User => PerUserAutostart::uninstall(),
+    };
+
+    match res {
+        Ok(()) => EXIT_SUCCESS,
+        Err(e) => {
+            error!(
+                "Error uninstalling {:?} autostart: {:?}",
+                execution_context, e
+            );
+            eprintln!(
+                "Error uninstalling {:?} autostart: {}",
+                execution_context, e
+            );
+            EXIT_INSTALL_ERROR
+        }
+    }
+}
+
+fn status(execution_context: ExecutionContext) -> u8 {
+    match TaskRunner::restore_from_disk(&execution_context) {
+        Some(state) => state.print_status(),
+        None => println!("No stored state for the {:?} context", execution_context),
+    }
+
+    EXIT_SUCCESS
+}
+
+fn plan(execution_context: ExecutionContext, repo_path: &Path, config: &Config) -> u8 {
+    let upn = resolve_identity(config, &execution_context).and_then(|i| i.upn);
+
+    match TaskFetcher::load_tasks(execution_context.clone(), repo_path, upn, config) {
+        Ok((tasks, report)) => {
+            TaskRunner::print_plan(&execution_context, &tasks.0, &report);
+            EXIT_SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to load tasks from {}: {}", repo_path.display(), e);
+            fetch_error_exit_code(e.as_ref())
+        }
+    }
+}
+
+fn validate(repo_path: &Path, config: &Config) -> u8 {
+    if !repo_path.is_dir() {
+        eprintln!("Repository {} does not exist", repo_path.display());
+        return EXIT_REPOSITORY_ERROR;
     }
 
-    let args: Vec<String> = env::args().collect();
+    let mut valid = true;
+    // the same in both contexts, printed once
+    let mut invalid = BTreeMap::new();
 
-    let own_username = env::var("USERNAME").expect("Failed to get username!");
-    let computername = env::var("COMPUTERNAME").expect("Failed to get the Computername");
+    for execution_context in [ExecutionContext::System, ExecutionContext::User] {
+        let upn = resolve_identity(config, &execution_context).and_then(|i| i.upn);
 
-    info!("Username: {} Computername: {}", own_username, computername);
+        match TaskFetcher::load_tasks(execution_context.clone(), repo_path, upn, config) {
+            Ok((tasks, report)) => {
+                println!("{:?}: {} tasks OK", execution_context, tasks.0.len());
+                invalid.extend(report.invalid.into_iter().map(|i| (i.path, i.error)));
+            }
+            Err(e) => {
+                println!("{:?}: {}", execution_context, e);
+                valid = false;
+            }
+        }
+    }
 
-    let username_without_dollar = own_username.as_str().split("$").next().unwrap();
+    for (path, error) in &invalid {
+        println!("{}: {}", path, error);
+    }
 
-    let execution_context = if computername == username_without_dollar {
-        ExecutionContext::System
+    if valid && invalid.is_empty() {
+        EXIT_SUCCESS
     } else {
-        ExecutionContext::User
+        EXIT_VALIDATION_FAILED
+    }
+}
+
+fn reset(execution_context: ExecutionContext) -> u8 {
+    TaskRunner::remove_disk_state(&execution_context);
+    println!(
+        "Removed the stored state of the {:?} context",
+        execution_context
+    );
+
+    EXIT_SUCCESS
+}
+
+/// The configuration and the repository checkout, the exit code if either cannot be determined.
+fn load_settings(
+    config_path: Option<&Path>,
+    repo_path: Option<PathBuf>,
+    execution_context: &ExecutionContext,
+) -> Result<(Config, PathBuf), u8> {
+    let config = match config_path {
+        Some(path) => Config::load(path),
+        None => Config::load_default(execution_context),
     };
 
-    let writer = {
-        let p = match execution_context {
-            ExecutionContext::System => get_programdata()?,
-            ExecutionContext::User => get_appdata_local()?,
-        };
+    let config = config.map_err(|e| {
+        error!("Failed to load the configuration: {}", e);
+        eprintln!("Failed to load the configuration: {}", e);
+        EXIT_CONFIG_ERROR
+    })?;
 
-        RollingFileAppender::builder()
+    let repo_path = match repo_path {
+        Some(p) => p,
+        None => TaskFetcher::default_repository_path(execution_context).map_err(|e| {
+            error!("Failed to determine the repository path: {}", e);
+            eprintln!("Failed to determine the repository path: {}", e);
+            EXIT_CONFIG_ERROR
+        })?,
+    };
+
+    Ok((config, repo_path))
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    if (cli.install || cli.uninstall) && cli.command.is_some() {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--install and --uninstall cannot be combined with a command",
+            )
+            .exit();
+    }
+
+    let command = match cli.command {
+        Some(c) => c,
+        None if cli.uninstall => Command::Uninstall,
+        None => Command::Run,
+    };
+
+    // running user tasks as SYSTEM must not happen, so an unknown context is an error;
+    // the logger depends on the context, so it is only printed
+    let execution_context = match detect_context(cli.context.clone(), &TokenContextDetector()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("{}, set the execution context with --context", e);
+            return ExitCode::from(EXIT_CONFIG_ERROR);
+        }
+    };
+
+    // detach from the console when started by the autostart or Intune, so no window pops up
+    #[cfg(windows)]
+    if matches!(
+        command,
+        Command::Run | Command::Install | Command::Uninstall
+    ) {
+        unsafe {
+            winapi::um::wincon::FreeConsole();
+        }
+    }
+
+    let log_path = match execution_context {
+        ExecutionContext::System => get_system_log_path(),
+        ExecutionContext::User => get_user_log_path(),
+    };
+
+    if let Ok(p) = &log_path {
+        fs::create_dir_all(p).ok();
+    }
+
+    let writer = match log_path {
+        Ok(p) => RollingFileAppender::builder()
             .rotation(Rotation::DAILY)
             .max_log_files(7)
             .filename_prefix("repo_task_run")
             .build(p)
-            .expect("Failed to build LogFileAppender")
+            .expect("Failed to build LogFileAppender"),
+        Err(e) => {
+            eprintln!("Failed to determine the log directory: {}", e);
+            return ExitCode::from(EXIT_CONFIG_ERROR);
+        }
     };
 
-    tracing_subscriber::fmt().with_writer(writer).init();
-
-    info!("Username: {} Computername: {}", own_username, computername);
-
-    if args.len() > 1 {
-        match args[1].as_str() {
-            "--install" => match execution_context {
-                ExecutionContext::System => {
-                    if let Err(e) = SystemAutostart::install() {
-                        error!("Error installing system autostart: {:?}", e);
-                        return Err(e);
-                    }
-                }
-                ExecutionContext::User => {
-                    if let Err(e) = PerUserAutostart::install() {
-                        error!("Error installing per-user autostart: {:?}", e);
-                        return Err(e);
-                    }
-                }
-            },
-            "--uninstall" => match execution_context {
-                ExecutionContext::System => SystemAutostart::uninstall()?,
-                ExecutionContext::User => PerUserAutostart::uninstall()?,
-            },
-            _ => (),
-        }
+    tracing_subscriber::fmt()
+        .with_max_level(cli.log_level)
+        .with_writer(writer)
+        .init();
+
+    if cli.context.is_some() {
+        warn!("Execution context is overridden by --context");
     }
 
-    info!("Running tasks...");
+    info!("Execution context: {:?}", execution_context);
 
-    let mut runner = TaskRunner::new(execution_context)?;
+    if cli.install {
+        let code = install(execution_context.clone());
 
-    runner.run();
+        if code != EXIT_SUCCESS {
+            return ExitCode::from(code);
+        }
+    }
+
+    let code = match command {
+        Command::Install => install(execution_context),
+        Command::Uninstall => uninstall(execution_context),
+        Command::Status => status(execution_context),
+        Command::Reset => reset(execution_context),
+        // only the commands reading the repository need the configuration
+        command => match load_settings(cli.config.as_deref(), cli.repo_path, &execution_context) {
+            Ok((config, repo_path)) => match command {
+                Command::Plan => plan(execution_context, &repo_path, &config),
+                Command::Validate => validate(&repo_path, &config),
+                _ => run(execution_context, &repo_path, &config),
+            },
+            Err(code) => code,
+        },
+    };
 
-    Ok(())
+    ExitCode::from(code)
 }
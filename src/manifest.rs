@@ -0,0 +1,341 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt::Display,
+    fs,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use crate::task::{ExecutionContext, TaskType};
+
+/// Repository-wide settings, located in the root of the repository.
+pub const REPO_MANIFEST_FILE_NAME: &str = "repo_task_run.toml";
+
+/// The newest `format_version` of the repository manifest this version understands.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// First and last line of the manifest block in a script header.
+const HEADER_START: &str = "#region repo_task_run";
+const HEADER_END: &str = "#endregion";
+
+/// Settings of a single task, from `<task>.toml` next to the script or the header block of the script.
+/// `type`, `context` and `reboot` override the folder rules, `depends` and the filters are added to them
+/// like the rules of another folder. Filter values starting with `!` exclude.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TaskManifest {
+    #[serde(rename = "type")]
+    pub type_: Option<TaskType>,
+    pub context: Option<ExecutionContext>,
+    pub reboot: Option<bool>,
+    /// takes precedence over the prefix of the file name
+    pub priority: Option<u32>,
+    pub depends: Vec<String>,
+    pub groups: Vec<String>,
+    pub group_ids: Vec<String>,
+    pub users: Vec<String>,
+    pub attributes: HashMap<String, Vec<String>>,
+    pub hosts: Vec<String>,
+    pub os: Vec<String>,
+    pub arch: Vec<String>,
+    /// The script is stopped if it runs longer.
+    pub timeout_secs: Option<u64>,
+    pub arguments: Vec<String>,
+    /// Exit codes counting as success, `[0]` if not set.
+    pub success_codes: Option<Vec<i32>>,
+}
+
+/// Settings of the whole repository from `repo_task_run.toml`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RepoManifest {
+    #[allow(unused)]
+    pub format_version: u32,
+    /// paths relative to the repository root (with `/`) or, without `/`, file and folder names
+    /// which are not read, wildcards as for `user-` rules
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    #[serde(default)]
+    pub defaults: TaskDefaults,
+    #[serde(default)]
+    pub filtered_dependency_policy: FilteredDependencyPolicy,
+    /// numeric prefixes of file names set the priority, `10-base.ps1` can also be referred to as `base`
+    #[serde(default)]
+    pub priority_prefixes: bool,
+}
+
+/// What happens to a task depending on a task which is left out by a rule or an invalid manifest
+/// or belongs to the other execution context.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilteredDependencyPolicy {
+    /// leave the task out as well
+    #[default]
+    Skip,
+    /// run the task without the dependency
+    Run,
+    /// load no task at all
+    Abort,
+}
+
+impl Default for RepoManifest {
+    fn default() -> Self {
+        RepoManifest {
+            format_version: FORMAT_VERSION,
+            ignore: Vec::new(),
+            defaults: TaskDefaults::default(),
+            filtered_dependency_policy: FilteredDependencyPolicy::default(),
+            priority_prefixes: false,
+        }
+    }
+}
+
+/// Settings of tasks which neither their folders nor their manifests set.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TaskDefaults {
+    #[serde(rename = "type")]
+    pub type_: Option<TaskType>,
+    pub context: Option<ExecutionContext>,
+    pub reboot: bool,
+    pub timeout_secs: Option<u64>,
+    pub success_codes: Option<Vec<i32>>,
+    pub priority: Option<u32>,
+}
+
+/// Only the version, read first so that newer manifests are rejected for their version
+/// and not for fields this version does not know.
+#[derive(Deserialize)]
+struct VersionOnly {
+    format_version: Option<u32>,
+}
+
+/// Reads the repository manifest in `dir`, the defaults if there is none.
+pub fn load_repo(dir: &Path) -> Result<RepoManifest, Box<dyn Error>> {
+    let path = dir.join(REPO_MANIFEST_FILE_NAME);
+
+    if !path.is_file() {
+        return Ok(RepoManifest::default());
+    }
+
+    let content = fs::read_to_string(&path)?;
+    let invalid = |e: toml::de::Error| format!("Invalid {}: {}", path.display(), e);
+
+    let version: VersionOnly = toml::from_str(&content).map_err(invalid)?;
+
+    match version.format_version {
+        Some(v) if (1..=FORMAT_VERSION).contains(&v) => (),
+        Some(v) => return Err(Box::new(ManifestError::UnsupportedFormatVersion(v))),
+        None => return Err(Box::new(ManifestError::MissingFormatVersion)),
+    }
+
+    Ok(toml::from_str(&content).map_err(invalid)?)
+}
+
+#[derive(Debug)]
+pub enum ManifestError {
+    MissingFormatVersion,
+    UnsupportedFormatVersion(u32),
+}
+
+impl Display for ManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ManifestError::MissingFormatVersion => {
+                write!(f, "{} has no format_version", REPO_MANIFEST_FILE_NAME)
+            }
+            ManifestError::UnsupportedFormatVersion(v) => write!(
+                f,
+                "{} has format_version {}, this version of RepoTaskRun supports up to {}, please update it",
+                REPO_MANIFEST_FILE_NAME, v, FORMAT_VERSION
+            ),
+        }
+    }
+}
+
+impl Error for ManifestError {}
+
+/// The sidecar manifest of the script at `path`, shared by all platform variants of the task.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    path.with_extension("toml")
+}
+
+/// Reads the manifest of the script at `path`. The sidecar takes precedence over the script header.
+pub fn load(path: &Path) -> Result<Option<TaskManifest>, Box<dyn Error>> {
+    let sidecar = sidecar_path(path);
+
+    if sidecar.is_file() {
+        let manifest = toml::from_str(&fs::read_to_string(&sidecar)?)
+            .map_err(|e| format!("Invalid {}: {}", sidecar.display(), e))?;
+
+        return Ok(Some(manifest));
+    }
+
+    match header_block(BufReader::new(fs::File::open(path)?))? {
+        Some(block) => {
+            Ok(Some(toml::from_str(&block).map_err(|e| {
+                format!("Invalid header of {}: {}", path.display(), e)
+            })?))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The TOML inside `#region repo_task_run` ... `#endregion`, without the leading `#`.
+/// The block has to come before the first line which is not a comment, only these lines are read.
+/// They are decoded lossily, scripts in other encodings (ANSI, UTF-16) have no header then.
+fn header_block(mut script: impl BufRead) -> io::Result<Option<String>> {
+    let mut block: Option<String> = None;
+    let mut buf = Vec::new();
+    let mut first = true;
+
+    loop {
+        buf.clear();
+
+        if script.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(None);
+        }
+
+        let line = String::from_utf8_lossy(&buf);
+        let line = if first {
+            first = false;
+            line.trim_start_matches('\u{feff}').trim()
+        } else {
+            line.trim()
+        };
+
+        if !line.is_empty() && !line.starts_with('#') {
+            return Ok(None);
+        }
+
+        match &mut block {
+            None if line.eq_ignore_ascii_case(HEADER_START) => block = Some(String::new()),
+            None => (),
+            Some(_) if line.eq_ignore_ascii_case(HEADER_END) => return Ok(block),
+            Some(block) => {
+                block.push_str(line.trim_start_matches('#'));
+                block.push('\n');
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(script: &[u8]) -> Option<String> {
+        header_block(script).unwrap()
+    }
+
+    #[test]
+    fn header_before_the_code() {
+        let script = b"\xef\xbb\xbf# Install\n\n#region repo_task_run\n# type = \"onboot\"\n#endregion\nWrite-Host\n";
+
+        assert_eq!(header(script).as_deref(), Some(" type = \"onboot\"\n"));
+        assert_eq!(
+            header(b"Write-Host\n#region repo_task_run\n#endregion\n"),
+            None
+        );
+        assert_eq!(
+            header(b"#region repo_task_run\n# type = \"onboot\"\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn other_encodings() {
+        // ANSI (Windows-1252) umlauts in the comments do not matter
+        let ansi = b"# Gr\xfc\xdfe\n#region repo_task_run\n# timeout_secs = 5\n#endregion\n\xe4\n";
+        let manifest: TaskManifest = toml::from_str(&header(ansi).unwrap()).unwrap();
+        assert_eq!(manifest.timeout_secs, Some(5));
+
+        // UTF-16 LE with BOM is no comment line
+        let utf16: Vec<u8> = "\u{feff}#region repo_task_run\r\n#endregion\r\n"
+            .encode_utf16()
+            .flat_map(|c| c.to_le_bytes())
+            .collect();
+        assert_eq!(header(&utf16), None);
+    }
+
+    #[test]
+    fn scripts_in_other_encodings_load() {
+        let path =
+            std::env::temp_dir().join(format!("repo_task_run_ansi_{}.ps1", std::process::id()));
+        fs::write(&path, b"# \xc4nderung\nWrite-Host \"\xfc\"\n").unwrap();
+
+        let manifest = load(&path);
+        fs::remove_file(&path).ok();
+
+        assert!(manifest.unwrap().is_none());
+    }
+
+    /// Loads `content` as the repository manifest of a temp directory.
+    fn repo_manifest(name: &str, content: &str) -> Result<RepoManifest, Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!(
+            "repo_task_run_manifest_{}_{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(REPO_MANIFEST_FILE_NAME), content).unwrap();
+
+        let manifest = load_repo(&dir);
+        fs::remove_dir_all(&dir).ok();
+
+        manifest
+    }
+
+    #[test]
+    fn repo_manifest_needs_a_known_format_version() {
+        let missing = repo_manifest("missing_version", "ignore = [\"drafts\"]\n").unwrap_err();
+        assert!(matches!(
+            missing.downcast_ref(),
+            Some(ManifestError::MissingFormatVersion)
+        ));
+
+        // a newer version is rejected for its version, not for fields this version does not know
+        let future = repo_manifest(
+            "future_version",
+            &format!("format_version = {}\nnew_setting = 1\n", FORMAT_VERSION + 1),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            future.downcast_ref(),
+            Some(ManifestError::UnsupportedFormatVersion(v)) if *v == FORMAT_VERSION + 1
+        ));
+
+        assert!(repo_manifest("zero_version", "format_version = 0\n").is_err());
+    }
+
+    #[test]
+    fn repo_manifest_settings() {
+        let manifest = repo_manifest(
+            "settings",
+            "format_version = 1\nignore = [\"drafts\", \"legacy/old-*\"]\n\n\
+             [defaults]\ncontext = \"system\"\ntype = \"oneshot\"\npriority = 20\n",
+        )
+        .unwrap();
+
+        assert_eq!(manifest.ignore, ["drafts", "legacy/old-*"]);
+        assert_eq!(manifest.defaults.context, Some(ExecutionContext::System));
+        assert!(matches!(manifest.defaults.type_, Some(TaskType::OneShot)));
+        assert_eq!(manifest.defaults.priority, Some(20));
+        assert!(matches!(
+            manifest.filtered_dependency_policy,
+            FilteredDependencyPolicy::Skip
+        ));
+
+        let dir = std::env::temp_dir().join(format!(
+            "repo_task_run_manifest_none_{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let default = load_repo(&dir).unwrap();
+        fs::remove_dir_all(&dir).ok();
+        assert!(default.ignore.is_empty());
+        assert_eq!(default.format_version, FORMAT_VERSION);
+    }
+}
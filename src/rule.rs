@@ -0,0 +1,219 @@
+//! Values of folder rules (`<kind>-<values>`).
+//!
+//! ```text
+//! values  = value *( "+" value )        ; any value may match (OR)
+//! value   = [ "!" ] 1*char              ; "!" excludes instead of includes
+//! char    = <any character but "+" and "%"> / escape
+//! escape  = "%" 2HEXDIG                 ; UTF-8 byte, f. e. "%2B" for "+", "%21" for a leading "!"
+//! ```
+//!
+//! A value starts with `!` only if it is negated, a literal leading `!` is written as `%21`.
+
+use std::{collections::HashSet, error::Error, fmt::Display, hash::Hash};
+
+#[derive(Debug)]
+pub enum RuleError {
+    EmptyValue,
+    InvalidEscape(String),
+    /// the value is not valid for the kind of rule
+    InvalidValue(String),
+}
+
+impl Display for RuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleError::EmptyValue => write!(f, "empty value"),
+            RuleError::InvalidEscape(value) => write!(f, "invalid escape sequence in {}", value),
+            RuleError::InvalidValue(value) => write!(f, "invalid value {}", value),
+        }
+    }
+}
+
+impl Error for RuleError {}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RuleValue {
+    pub negated: bool,
+    /// the value with its escape sequences decoded
+    pub value: String,
+}
+
+/// Splits the values of a rule and decodes them.
+pub fn parse_values(values: &str) -> Result<Vec<RuleValue>, RuleError> {
+    values
+        .split('+')
+        .map(|value| {
+            let (negated, value) = match value.strip_prefix('!') {
+                Some(value) => (true, value),
+                None => (false, value),
+            };
+
+            if value.is_empty() {
+                return Err(RuleError::EmptyValue);
+            }
+
+            Ok(RuleValue {
+                negated,
+                value: decode(value)?,
+            })
+        })
+        .collect()
+}
+
+/// A single value given as is, f. e. in a manifest: no lists and no escaping, a leading `!` negates.
+pub fn literal_value(value: &str) -> RuleValue {
+    match value.strip_prefix('!') {
+        Some(value) => RuleValue {
+            negated: true,
+            value: value.to_string(),
+        },
+        None => RuleValue {
+            negated: false,
+            value: value.to_string(),
+        },
+    }
+}
+
+/// Decodes the `%XX` escape sequences of a value.
+pub fn decode(value: &str) -> Result<String, RuleError> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let byte = tail
+                .get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| RuleError::InvalidEscape(value.to_string()))?;
+
+            bytes.push(byte);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| RuleError::InvalidEscape(value.to_string()))
+}
+
+/// Included and excluded values of a rule. It matches if one of the included values matches,
+/// or none are included, and none of the excluded values matches.
+#[derive(Clone, Debug)]
+pub struct Filter<T> {
+    pub include: HashSet<T>,
+    pub exclude: HashSet<T>,
+}
+
+impl<T> Default for Filter<T> {
+    fn default() -> Self {
+        Filter {
+            include: HashSet::new(),
+            exclude: HashSet::new(),
+        }
+    }
+}
+
+impl<T: Eq + Hash> Filter<T> {
+    pub fn insert(&mut self, negated: bool, value: T) {
+        if negated {
+            self.exclude.insert(value);
+        } else {
+            self.include.insert(value);
+        }
+    }
+
+    pub fn matches(&self, is_match: impl Fn(&T) -> bool) -> bool {
+        (self.include.is_empty() || self.include.iter().any(&is_match))
+            && !self.exclude.iter().any(&is_match)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(negated: bool, value: &str) -> RuleValue {
+        RuleValue {
+            negated,
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn plus_separates_values() {
+        assert_eq!(
+            parse_values("sales+marketing").unwrap(),
+            [value(false, "sales"), value(false, "marketing")]
+        );
+        assert_eq!(parse_values("sales").unwrap(), [value(false, "sales")]);
+    }
+
+    #[test]
+    fn leading_bang_negates() {
+        assert_eq!(
+            parse_values("!contractors+staff").unwrap(),
+            [value(true, "contractors"), value(false, "staff")]
+        );
+        // only the leading one
+        assert_eq!(parse_values("a!b").unwrap(), [value(false, "a!b")]);
+    }
+
+    #[test]
+    fn escapes_are_decoded() {
+        assert_eq!(parse_values("a%2Bb").unwrap(), [value(false, "a+b")]);
+        assert_eq!(parse_values("%21bang").unwrap(), [value(false, "!bang")]);
+        assert_eq!(parse_values("!%21bang").unwrap(), [value(true, "!bang")]);
+        assert_eq!(
+            parse_values("vpn%2Finstall").unwrap(),
+            [value(false, "vpn/install")]
+        );
+        assert_eq!(parse_values("%25").unwrap(), [value(false, "%")]);
+        assert_eq!(decode("M%C3%BCnchen").unwrap(), "München");
+    }
+
+    #[test]
+    fn invalid_escapes() {
+        assert!(matches!(decode("a%2"), Err(RuleError::InvalidEscape(_))));
+        assert!(matches!(decode("a%"), Err(RuleError::InvalidEscape(_))));
+        assert!(matches!(decode("%zz"), Err(RuleError::InvalidEscape(_))));
+        // a lone continuation byte and a truncated sequence are no UTF-8
+        assert!(matches!(decode("%80"), Err(RuleError::InvalidEscape(_))));
+        assert!(matches!(decode("%C3"), Err(RuleError::InvalidEscape(_))));
+        assert!(parse_values("ok+bad%2").is_err());
+    }
+
+    #[test]
+    fn empty_values() {
+        for values in ["", "+", "a+", "+a", "a++b", "!", "a+!"] {
+            assert!(
+                matches!(parse_values(values), Err(RuleError::EmptyValue)),
+                "{:?}",
+                values
+            );
+        }
+    }
+
+    #[test]
+    fn literal_values_are_not_split_or_decoded() {
+        assert_eq!(literal_value("a+b%2B"), value(false, "a+b%2B"));
+        assert_eq!(literal_value("!a+b"), value(true, "a+b"));
+    }
+
+    #[test]
+    fn filter_matching() {
+        let mut filter = Filter::default();
+        assert!(filter.matches(|_: &&str| false), "an empty filter matches");
+
+        filter.insert(true, "contractors");
+        assert!(filter.matches(|v| *v == "sales"), "only exclusions");
+        assert!(!filter.matches(|v| *v == "contractors"));
+
+        filter.insert(false, "sales");
+        filter.insert(false, "marketing");
+        assert!(filter.matches(|v| *v == "marketing"));
+        assert!(!filter.matches(|v| *v == "it"));
+        assert!(!filter.matches(|v| *v == "sales" || *v == "contractors"));
+    }
+}
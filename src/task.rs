@@ -1,74 +1,241 @@
 use core::str;
 use log::error;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, path::PathBuf, process::Command};
+
+use crate::group_cache::MembershipSource;
+use std::{
+    collections::HashSet,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    str::FromStr,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// The platform-specific variants a task script can come in.
+/// `install-vpn.ps1` and `install-vpn.sh` are variants of the same task `install-vpn`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScriptKind {
+    PowerShell,
+    Shell,
+}
+
+impl ScriptKind {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?;
+
+        if extension.eq_ignore_ascii_case("ps1") {
+            Some(ScriptKind::PowerShell)
+        } else if extension.eq_ignore_ascii_case("sh") {
+            Some(ScriptKind::Shell)
+        } else {
+            None
+        }
+    }
+
+    /// The variant which gets executed on this platform.
+    #[cfg(windows)]
+    pub fn native() -> Self {
+        ScriptKind::PowerShell
+    }
+
+    /// The variant which gets executed on this platform.
+    #[cfg(target_os = "linux")]
+    pub fn native() -> Self {
+        ScriptKind::Shell
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ExecutionContext {
     System,
     User,
 }
 
+impl FromStr for ExecutionContext {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system" => Ok(ExecutionContext::System),
+            "user" => Ok(ExecutionContext::User),
+            _ => Err(format!(
+                "unknown execution context \"{}\", expected system or user",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum TaskType {
     OneShot,
     OnBoot,
 }
 
+/// A group a task is restricted to, either by object id (`groupid-<id>` or an alias) or by display name (`group-<name>`).
+/// Both are stored lowercase, since they are compared case-insensitively.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum GroupRef {
+    Id(String),
+    Name(String),
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Task {
     pub type_: TaskType,
+    /// the path relative to the repository root without extension, unique unlike the name
+    pub id: String,
     pub name: String,
     pub depends_on: Option<HashSet<String>>,
     pub context: ExecutionContext,
     pub user_filter: Option<HashSet<String>>,
-    pub group_filter: Option<HashSet<String>>,
+    pub group_filter: Option<HashSet<GroupRef>>,
     pub reboot_required: bool,
     pub executable: PathBuf,
     pub hash: String,
+    pub arguments: Vec<String>,
+    pub timeout_secs: Option<u64>,
+    pub success_codes: Vec<i32>,
+    /// tasks with a lower priority run first, as far as the dependencies allow it
+    pub priority: u32,
 }
 
+/// The priority of tasks without a prefix (`10-base.ps1`) or a `priority` in their manifest.
+pub const DEFAULT_PRIORITY: u32 = 50;
+
 impl Task {
-    pub fn run(&self) -> bool {
-        match Command::new("powershell.exe")
-            .current_dir(self.executable.parent().unwrap())
+    #[cfg(windows)]
+    fn command(&self) -> Command {
+        let mut cmd = Command::new("powershell.exe");
+
+        cmd.current_dir(self.executable.parent().unwrap())
             .arg("-WindowStyle")
             .arg("hidden")
             .arg(format!(
-                "Set-ExecutionPolicy -Scope CurrentUser -ExecutionPolicy Bypass; & \"{}\"",
-                self.executable.display()
-            ))
-            .output()
+                // without the explicit exit, powershell.exe only reports 0 or 1
+                "Set-ExecutionPolicy -Scope CurrentUser -ExecutionPolicy Bypass; & \"{}\"{}; \
+                 if (-not $?) {{ exit $(if ($LASTEXITCODE) {{ $LASTEXITCODE }} else {{ 1 }}) }}",
+                self.executable.display(),
+                self.arguments
+                    .iter()
+                    .map(|a| format!(" '{}'", a.replace('\'', "''")))
+                    .collect::<String>()
+            ));
+
+        cmd
+    }
+
+    #[cfg(target_os = "linux")]
+    fn command(&self) -> Command {
+        let mut cmd = Command::new("/bin/sh");
+
+        cmd.current_dir(self.executable.parent().unwrap())
+            .arg(&self.executable)
+            .args(&self.arguments);
+
+        cmd
+    }
+
+    /// Runs the script, it fails if it exits with a code not in `success_codes` or exceeds `timeout_secs`.
+    pub fn run(&self) -> bool {
+        let mut child = match self
+            .command()
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
         {
-            Ok(output) => {
-                if !output.status.success() {
-                    error!(
-                        "Exit status of task \"{}\" (path: {}) is nonzero: {}",
-                        self.name,
-                        self.executable.display(),
-                        output.status
-                    );
-                    error!(
-                        "Stderr: {}",
-                        str::from_utf8(&output.stderr).unwrap_or("<UTF8 Error>")
-                    );
-                    error!(
-                        "Stdout: {}",
-                        str::from_utf8(&output.stdout).unwrap_or("<UTF8 Error>")
-                    );
-                    false
-                } else {
-                    true
-                }
-            }
+            Ok(child) => child,
             Err(e) => {
                 eprintln!(
                     "Failed to spawn process for task {} (execeutable: {}): {:?}",
-                    self.name,
+                    self.id,
                     self.executable.display(),
                     e
                 );
-                false
+                return false;
+            }
+        };
+
+        // the pipes are drained while waiting, a full pipe would block the script
+        let stdout = child.stdout.take().map(Self::drain);
+        let stderr = child.stderr.take().map(Self::drain);
+
+        let status = match self.timeout_secs {
+            Some(secs) => Self::wait_with_timeout(&mut child, Duration::from_secs(secs)),
+            None => child.wait().map_err(|e| e.to_string()),
+        };
+
+        let status = match status {
+            Ok(status) => status,
+            Err(reason) => {
+                error!(
+                    "Task \"{}\" (path: {}) failed: {}",
+                    self.id,
+                    self.executable.display(),
+                    reason
+                );
+                return false;
+            }
+        };
+
+        if status
+            .code()
+            .is_some_and(|c| self.success_codes.contains(&c))
+        {
+            return true;
+        }
+
+        let stdout = stdout.and_then(|h| h.join().ok()).unwrap_or_default();
+        let stderr = stderr.and_then(|h| h.join().ok()).unwrap_or_default();
+
+        error!(
+            "Exit status of task \"{}\" (path: {}) is not one of {:?}: {}",
+            self.id,
+            self.executable.display(),
+            self.success_codes,
+            status
+        );
+        error!(
+            "Stderr: {}",
+            str::from_utf8(&stderr).unwrap_or("<UTF8 Error>")
+        );
+        error!(
+            "Stdout: {}",
+            str::from_utf8(&stdout).unwrap_or("<UTF8 Error>")
+        );
+
+        false
+    }
+
+    fn drain(mut pipe: impl Read + Send + 'static) -> JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            pipe.read_to_end(&mut buf).ok();
+            buf
+        })
+    }
+
+    /// Polls the script until it exits, it is killed once it runs longer than `timeout`.
+    fn wait_with_timeout(child: &mut Child, timeout: Duration) -> Result<ExitStatus, String> {
+        let started = Instant::now();
+
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => return Ok(status),
+                Ok(None) if started.elapsed() >= timeout => {
+                    child.kill().ok();
+                    child.wait().ok();
+                    return Err(format!(
+                        "did not finish within {} seconds and was stopped",
+                        timeout.as_secs()
+                    ));
+                }
+                Ok(None) => thread::sleep(Duration::from_millis(250)),
+                Err(e) => return Err(e.to_string()),
             }
         }
     }
@@ -76,3 +243,26 @@ impl Task {
 
 #[derive(Debug)]
 pub struct Tasks(pub Vec<Task>);
+
+/// How the tasks were selected, shown by `plan`.
+#[derive(Debug, Default)]
+pub struct FetchReport {
+    pub group_membership: MembershipSource,
+    /// tasks left out by a user or group rule
+    pub skipped: Vec<SkippedTask>,
+    /// scripts and folders left out, since their manifest or rule is invalid
+    pub invalid: Vec<InvalidEntry>,
+}
+
+#[derive(Debug)]
+pub struct InvalidEntry {
+    /// relative to the repository root
+    pub path: String,
+    pub error: String,
+}
+
+#[derive(Debug)]
+pub struct SkippedTask {
+    pub id: String,
+    pub reason: String,
+}
@@ -1,13 +1,29 @@
 use crate::{
     common::{get_system_repository_path, get_user_repository_path, is_host_reachable, REPO_HOST},
-    entra_groups::get_entra_groups_of_user,
+    config::{Config, GroupFailurePolicy},
+    device::{get_device_id, DeviceFactsProvider, DeviceRule, NativeDeviceFactsProvider},
+    entra_groups::get_entra_user_attributes,
     gix_repository::update_repo,
-    task::{ExecutionContext, Task, TaskType, Tasks},
+    graph::{GraphClient, GraphError},
+    group_cache::{self, MembershipSource},
+    group_provider::{
+        build_group_provider, GroupMembership, GroupPrincipal, GroupProvider, GroupProviderError,
+    },
+    manifest::{self, FilteredDependencyPolicy, TaskManifest},
+    rule::{decode, literal_value, parse_values, Filter, RuleError, RuleValue},
+    task::{
+        ExecutionContext, FetchReport, GroupRef, InvalidEntry, ScriptKind, SkippedTask, Task,
+        TaskType, Tasks, DEFAULT_PRIORITY,
+    },
+    wildcard,
 };
-use log::{info, warn};
+use log::{error, info, warn};
 use sha256::TrySha256Digest;
 use std::{
-    collections::{HashSet, VecDeque},
+    cell::OnceCell,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    ffi::OsString,
     fs,
     path::{Path, PathBuf},
     time::Duration,
@@ -16,33 +32,138 @@ use std::{error::Error, fmt::Display};
 
 #[derive(Debug)]
 pub enum TaskFetchterError {
-    CircularDependecy,
+    /// the ids of the tasks forming the cycle, the first one repeated at the end
+    Cycle(Vec<String>),
+    /// `depends-` references matching the name of several tasks: the reference -> ids of the tasks
+    AmbiguousDependencies(Vec<(String, Vec<String>)>),
+    /// references to tasks which are no tasks of this execution context: the id of the task -> the reference
+    MissingDependencies(Vec<(String, String)>),
+    /// a task depends on a task left out by a rule or an invalid manifest or of the other execution context
+    /// and `filtered_dependency_policy` is `abort`
+    FilteredDependency { task: String, dependency: String },
+    /// the group lookup failed and `group_failure_policy` is `abort`
+    GroupResolutionFailed(GroupProviderError),
+    /// the user attribute lookup failed and `group_failure_policy` is `abort`
+    AttributeResolutionFailed(GraphError),
 }
 
 impl Display for TaskFetchterError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "TaskFetcherError")
+        match self {
+            TaskFetchterError::Cycle(ids) => {
+                write!(
+                    f,
+                    "Tasks with circular dependencies cannot be ordered: {}",
+                    ids.join(" -> ")
+                )
+            }
+            TaskFetchterError::AmbiguousDependencies(references) => {
+                write!(
+                    f,
+                    "Dependencies match several tasks, refer to them by their id instead:"
+                )?;
+                for (reference, ids) in references {
+                    write!(f, " {} ({})", reference, ids.join(", "))?;
+                }
+                Ok(())
+            }
+            TaskFetchterError::MissingDependencies(references) => {
+                write!(f, "Dependencies are no tasks of this execution context:")?;
+                for (task, reference) in references {
+                    write!(f, " {} depends on {}", task, reference)?;
+                }
+                Ok(())
+            }
+            TaskFetchterError::FilteredDependency { task, dependency } => {
+                write!(f, "{} depends on {}, which is left out", task, dependency)
+            }
+            TaskFetchterError::GroupResolutionFailed(e) => {
+                write!(f, "Group membership could not be resolved, aborting: {}", e)
+            }
+            TaskFetchterError::AttributeResolutionFailed(e) => {
+                write!(f, "User attributes could not be resolved, aborting: {}", e)
+            }
+        }
     }
 }
 
 impl Error for TaskFetchterError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        None
+        match self {
+            TaskFetchterError::GroupResolutionFailed(e) => Some(e),
+            TaskFetchterError::AttributeResolutionFailed(e) => Some(e),
+            _ => None,
+        }
     }
+}
 
-    fn description(&self) -> &str {
-        match self {
-            TaskFetchterError::CircularDependecy => {
-                "Tasks with circular dependencies cannot be ordered."
-            }
+/// Maps friendly group names (`group-<alias>`) to group object ids, located in the root of the repository.
+pub const GROUP_ALIASES_FILE_NAME: &str = "group_aliases.toml";
+
+/// The principal of the group rules and its membership, looked up when the first task with a group rule
+/// is evaluated and kept for the rest of the run. Repositories without group rules need no lookup.
+struct LazyGroupMembership<'a> {
+    wanted_execution_context: &'a ExecutionContext,
+    upn: Option<&'a str>,
+    config: &'a Config,
+    resolved: OnceCell<ResolvedGroups>,
+}
+
+struct ResolvedGroups {
+    principal: Option<GroupPrincipal>,
+    membership: Option<GroupMembership>,
+    source: MembershipSource,
+}
+
+impl LazyGroupMembership<'_> {
+    fn get(&self) -> Result<&ResolvedGroups, Box<dyn Error>> {
+        if self.resolved.get().is_none() {
+            let principal = TaskFetcher::group_principal(
+                self.wanted_execution_context,
+                self.upn.map(|u| u.to_string()),
+                self.config,
+            );
+
+            let (membership, source) = match &principal {
+                Some(p) => {
+                    let provider =
+                        build_group_provider(self.config, self.wanted_execution_context)?;
+                    TaskFetcher::resolve_group_membership(
+                        self.wanted_execution_context,
+                        &provider,
+                        p,
+                        self.config,
+                    )?
+                }
+                None => (None, MembershipSource::None),
+            };
+
+            info!(
+                "group_membership of {:?} ({}): {:?}",
+                &principal, source, membership
+            );
+
+            let _ = self.resolved.set(ResolvedGroups {
+                principal,
+                membership,
+                source,
+            });
         }
+
+        Ok(self.resolved.get().unwrap())
     }
 
-    fn cause(&self) -> Option<&dyn Error> {
-        None
+    fn source(&self) -> MembershipSource {
+        self.resolved
+            .get()
+            .map(|resolved| resolved.source.clone())
+            .unwrap_or(MembershipSource::NotNeeded)
     }
 }
 
+/// `attr-<attribute>-<patterns>` rules: the attribute as written in the rule -> patterns of its value.
+type AttributeFilter = HashMap<String, Filter<String>>;
+
 pub struct TaskFetcher();
 
 #[derive(Debug)]
@@ -51,21 +172,31 @@ pub struct StackEntry {
     tasktype: Option<TaskType>,
     context: Option<ExecutionContext>,
     depends_on: Option<HashSet<String>>,
-    user_filter: Option<HashSet<String>>,
-    group_filter: Option<HashSet<String>>,
+    user_filter: Option<Filter<String>>,
+    group_filter: Option<Filter<GroupRef>>,
+    attr_filter: Option<AttributeFilter>,
+    /// one filter per folder, nested device rules must all match
+    device_filter: Option<Vec<Filter<DeviceRule>>>,
     reboot_required: bool,
 }
 
 impl TaskFetcher {
+    pub fn default_repository_path(
+        execution_context: &ExecutionContext,
+    ) -> Result<PathBuf, Box<dyn Error>> {
+        Ok(match execution_context {
+            ExecutionContext::System => get_system_repository_path()?,
+            ExecutionContext::User => get_user_repository_path()?,
+        })
+    }
+
+    /// Updates the repository at `repo_path` and builds the tasks from it.
     pub fn fetch_tasks(
         wanted_execution_context: ExecutionContext,
+        repo_path: &Path,
         upn: Option<String>,
+        config: &Config,
     ) -> Result<(Tasks, bool), Box<dyn Error>> {
-        let repo_path = match wanted_execution_context {
-            ExecutionContext::System => get_system_repository_path()?,
-            ExecutionContext::User => get_user_repository_path()?,
-        };
-
         info!("Updating repo...");
         let has_changed = {
             while !is_host_reachable(REPO_HOST) {
@@ -75,49 +206,658 @@ impl TaskFetcher {
                 );
                 std::thread::sleep(Duration::from_secs(10));
             }
-            update_repo(&repo_path)?
+            update_repo(repo_path)?
         };
 
+        let (tasks, _) = Self::load_tasks(wanted_execution_context, repo_path, upn, config)?;
+
+        Ok((tasks, has_changed))
+    }
+
+    /// Builds the tasks from the current checkout at `repo_path`, without updating it.
+    pub fn load_tasks(
+        wanted_execution_context: ExecutionContext,
+        repo_path: &Path,
+        upn: Option<String>,
+        config: &Config,
+    ) -> Result<(Tasks, FetchReport), Box<dyn Error>> {
+        if !repo_path.is_dir() {
+            return Err(format!("Repository {} does not exist", repo_path.display()).into());
+        }
+
         info!("Building tasks from repo...");
 
-        match Self::build_tasks_from_directory(&repo_path, wanted_execution_context, upn) {
-            Some(tasks) => Ok((tasks, has_changed)),
-            None => Err(Box::new(TaskFetchterError::CircularDependecy)),
+        Self::build_tasks_from_directory(
+            repo_path,
+            wanted_execution_context,
+            upn,
+            &NativeDeviceFactsProvider(),
+            config,
+        )
+    }
+
+    /// Reads the group aliases, keys and ids are lowercased.
+    fn load_group_aliases(dir: &Path) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        let path = dir.join(GROUP_ALIASES_FILE_NAME);
+
+        if !path.is_file() {
+            return Ok(HashMap::new());
+        }
+
+        let aliases: HashMap<String, String> = toml::from_str(&fs::read_to_string(&path)?)
+            .map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+
+        Ok(aliases
+            .into_iter()
+            .map(|(alias, id)| (alias.to_lowercase(), id.to_lowercase()))
+            .collect())
+    }
+
+    /// Group rules are matched against the user in user context and against the device in system context.
+    fn group_principal(
+        wanted_execution_context: &ExecutionContext,
+        upn: Option<String>,
+        config: &Config,
+    ) -> Option<GroupPrincipal> {
+        match wanted_execution_context {
+            ExecutionContext::User => upn.map(GroupPrincipal::User),
+            ExecutionContext::System => get_device_id(&config.device).map(GroupPrincipal::Device),
+        }
+    }
+
+    /// Looks up the groups of `principal`, a failed lookup is handled according to `group_failure_policy`.
+    fn resolve_group_membership(
+        wanted_execution_context: &ExecutionContext,
+        provider: &dyn GroupProvider,
+        principal: &GroupPrincipal,
+        config: &Config,
+    ) -> Result<(Option<GroupMembership>, MembershipSource), TaskFetchterError> {
+        let e = match provider.groups_of(principal) {
+            Ok(membership) => {
+                if config.group_cache.enabled {
+                    group_cache::store(wanted_execution_context, principal, &membership);
+                }
+
+                return Ok((Some(membership), MembershipSource::Live));
+            }
+            Err(e) => e,
+        };
+
+        error!("Failed to look up the groups of {}: {}", principal, e);
+
+        match config.group_failure_policy {
+            GroupFailurePolicy::Abort => Err(TaskFetchterError::GroupResolutionFailed(e)),
+            GroupFailurePolicy::Skip => {
+                warn!("Skipping all group-restricted tasks");
+                Ok((None, MembershipSource::None))
+            }
+            GroupFailurePolicy::Cache if config.group_cache.enabled => {
+                match group_cache::load(
+                    wanted_execution_context,
+                    principal,
+                    config.group_cache.ttl_hours,
+                ) {
+                    Some((membership, fetched_at)) => {
+                        Ok((Some(membership), MembershipSource::Cached { fetched_at }))
+                    }
+                    None => {
+                        warn!("No cached group membership, skipping all group-restricted tasks");
+                        Ok((None, MembershipSource::None))
+                    }
+                }
+            }
+            GroupFailurePolicy::Cache => {
+                warn!("Group cache is disabled, skipping all group-restricted tasks");
+                Ok((None, MembershipSource::None))
+            }
+        }
+    }
+
+    /// Looks up the profile attributes the `attr-` rules refer to. Without a UPN or if the lookup fails,
+    /// attribute-restricted tasks are skipped, unless `group_failure_policy` is `abort`.
+    fn resolve_user_attributes(
+        wanted_execution_context: &ExecutionContext,
+        upn: Option<&str>,
+        attributes: &HashSet<String>,
+        config: &Config,
+    ) -> Result<Option<HashMap<String, Vec<String>>>, TaskFetchterError> {
+        let Some(upn) = upn else {
+            return Ok(None);
+        };
+
+        let graph = GraphClient::new(&config.entra, &config.graph, wanted_execution_context);
+
+        match get_entra_user_attributes(&graph, upn, attributes) {
+            Ok(values) => Ok(Some(values)),
+            Err(e) => {
+                error!("Failed to look up the attributes of {}: {}", upn, e);
+
+                match config.group_failure_policy {
+                    GroupFailurePolicy::Abort => {
+                        Err(TaskFetchterError::AttributeResolutionFailed(e))
+                    }
+                    _ => {
+                        warn!("Skipping all attribute-restricted tasks");
+                        Ok(None)
+                    }
+                }
+            }
         }
     }
 
+    /// Why the user attributes do not satisfy the `attr-` rules, `None` if they do.
+    /// Every attribute has to match one of its patterns.
+    fn attr_filter_mismatch(
+        attr_filter: &AttributeFilter,
+        attributes: Option<&HashMap<String, Vec<String>>>,
+    ) -> Option<String> {
+        let Some(attributes) = attributes else {
+            return Some("the user attributes are not known".into());
+        };
+
+        attr_filter.iter().find_map(|(attribute, patterns)| {
+            let values = attributes
+                .get(&attribute.to_lowercase())
+                .map(|v| v.as_slice())
+                .unwrap_or_default();
+
+            if patterns.matches(|p| values.iter().any(|value| wildcard::matches(p, value))) {
+                None
+            } else {
+                Some(format!(
+                    "{} {:?} does not match {:?}",
+                    attribute, values, patterns
+                ))
+            }
+        })
+    }
+
+    /// The path relative to `root` with `/` as separator.
+    fn relative_path(root: &Path, path: &Path) -> String {
+        path.strip_prefix(root)
+            .unwrap_or(path)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Patterns with `/` are matched against the path relative to `root`, the others against the name.
+    fn is_ignored(root: &Path, path: &Path, patterns: &[String]) -> bool {
+        let relative = Self::relative_path(root, path);
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy())
+            .unwrap_or_default();
+
+        patterns.iter().any(|pattern| {
+            if pattern.contains('/') {
+                wildcard::matches(pattern.trim_matches('/'), &relative)
+            } else {
+                wildcard::matches(pattern, &name)
+            }
+        })
+    }
+
+    /// The file name without extension.
+    fn task_name(path: &Path) -> String {
+        path.file_stem().unwrap().to_str().unwrap().to_string()
+    }
+
+    /// The names dependencies can refer to a task by: the task name and, if the repository uses
+    /// priority prefixes, the name without the prefix.
+    fn dependency_names(path: &Path, priority_prefixes: bool) -> Vec<String> {
+        let name = Self::task_name(path);
+
+        match Self::priority_prefix(&name) {
+            Some((_, unprefixed)) if priority_prefixes => {
+                let unprefixed = unprefixed.to_string();
+                vec![name, unprefixed]
+            }
+            _ => vec![name],
+        }
+    }
+
+    /// Splits `10-base` into the priority 10 and the name `base`.
+    fn priority_prefix(stem: &str) -> Option<(u32, &str)> {
+        let (prefix, name) = stem.split_once('-')?;
+
+        if name.is_empty() || !prefix.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+
+        Some((prefix.parse().ok()?, name))
+    }
+
+    /// The path relative to `root` without extension, the same for all platform variants of a task.
+    fn task_id(root: &Path, path: &Path) -> String {
+        Self::relative_path(root, &path.with_extension(""))
+    }
+
+    /// Maps the `depends-` references to task ids. A reference is either an id or the name of exactly one
+    /// task of this execution context, otherwise of the left out ones. `references` maps each reference to
+    /// the ids of the tasks using it.
+    fn resolve_dependencies(
+        references: &HashMap<String, Vec<String>>,
+        tasks_by_name: &HashMap<String, Vec<String>>,
+        left_out_tasks_by_name: &HashMap<String, Vec<String>>,
+    ) -> Result<HashMap<String, String>, TaskFetchterError> {
+        let mut resolved = HashMap::new();
+        let mut ambiguous = Vec::new();
+        let mut missing = Vec::new();
+
+        for (reference, referencing_tasks) in references {
+            match Self::resolve_reference(reference, tasks_by_name)
+                .or_else(|| Self::resolve_reference(reference, left_out_tasks_by_name))
+            {
+                Some(Ok(id)) => {
+                    resolved.insert(reference.clone(), id);
+                }
+                Some(Err(candidates)) => ambiguous.push((reference.clone(), candidates)),
+                None => {
+                    for task in referencing_tasks {
+                        missing.push((task.clone(), reference.clone()));
+                    }
+                }
+            }
+        }
+
+        if !ambiguous.is_empty() {
+            ambiguous.sort();
+            return Err(TaskFetchterError::AmbiguousDependencies(ambiguous));
+        }
+
+        if !missing.is_empty() {
+            missing.sort();
+            return Err(TaskFetchterError::MissingDependencies(missing));
+        }
+
+        Ok(resolved)
+    }
+
+    /// Registers the task at `path` as left out before its rules are known, dependencies on it
+    /// follow `filtered_dependency_policy`. `note` tells why in the report.
+    fn register_left_out(
+        left_out_tasks_by_name: &mut HashMap<String, Vec<String>>,
+        left_out_notes: &mut HashMap<String, String>,
+        root: &Path,
+        path: &Path,
+        priority_prefixes: bool,
+        note: String,
+    ) {
+        let id = Self::task_id(root, path);
+
+        for name in Self::dependency_names(path, priority_prefixes) {
+            left_out_tasks_by_name
+                .entry(name)
+                .or_default()
+                .push(id.clone());
+        }
+        left_out_notes.insert(id, note);
+    }
+
+    /// The scripts of this platform in `folder` and its subfolders which are not ignored.
+    fn native_scripts(root: &Path, folder: &Path, ignore: &[String]) -> Vec<PathBuf> {
+        let mut scripts = Vec::new();
+        let mut folders = vec![folder.to_path_buf()];
+
+        while let Some(folder) = folders.pop() {
+            for path in fs::read_dir(&folder)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|e| e.path())
+            {
+                if Self::is_ignored(root, &path, ignore) {
+                    continue;
+                }
+
+                if path.is_dir() {
+                    folders.push(path);
+                } else if ScriptKind::from_path(&path) == Some(ScriptKind::native()) {
+                    scripts.push(path);
+                }
+            }
+        }
+
+        scripts
+    }
+
+    /// The id `reference` refers to among `tasks_by_name`, the sorted candidates if it is ambiguous
+    /// and `None` if it matches no task.
+    fn resolve_reference(
+        reference: &String,
+        tasks_by_name: &HashMap<String, Vec<String>>,
+    ) -> Option<Result<String, Vec<String>>> {
+        if tasks_by_name.values().flatten().any(|id| id == reference) {
+            return Some(Ok(reference.clone()));
+        }
+
+        match tasks_by_name.get(reference)?.as_slice() {
+            [id] => Some(Ok(id.clone())),
+            candidates => {
+                let mut candidates = candidates.to_vec();
+                candidates.sort();
+                Some(Err(candidates))
+            }
+        }
+    }
+
+    /// Why the UPN does not match the `user-<patterns>` rules, `None` if it does.
+    /// A pattern without `@` is matched against the part of the UPN before the `@`.
+    fn user_filter_mismatch(user_filter: &Filter<String>, upn: Option<&str>) -> Option<String> {
+        let Some(upn) = upn else {
+            return Some("no UPN is known for the user rules".into());
+        };
+
+        let account = upn.split('@').next().unwrap_or(upn);
+        let matched = user_filter.matches(|pattern| {
+            if pattern.contains('@') {
+                wildcard::matches(pattern, upn)
+            } else {
+                wildcard::matches(pattern, account)
+            }
+        });
+
+        if matched {
+            None
+        } else {
+            Some(format!(
+                "{} does not match the user rules {:?}",
+                upn, user_filter
+            ))
+        }
+    }
+
+    /// Applies the rule of the folder `name` to `entry`, see `rule` for the syntax of the values.
+    /// Folders without a known rule are plain folders.
+    fn apply_folder_rule(
+        entry: &mut StackEntry,
+        name: &str,
+        group_aliases: &HashMap<String, String>,
+    ) -> Result<(), RuleError> {
+        let Some((kind, values)) = name.split_once('-') else {
+            return Ok(());
+        };
+
+        match (kind, values) {
+            ("attr", rule) => {
+                let (attribute, values) = rule
+                    .split_once('-')
+                    .filter(|(attribute, _)| !attribute.is_empty())
+                    .ok_or_else(|| RuleError::InvalidValue(rule.to_string()))?;
+
+                Self::apply_attr_values(entry, decode(attribute)?, parse_values(values)?);
+            }
+            (
+                "group" | "groupid" | "user" | "host" | "os" | "arch" | "depends" | "reset",
+                values,
+            ) => {
+                Self::apply_rule_values(entry, kind, parse_values(values)?, group_aliases)?;
+            }
+            ("context", "system") => {
+                entry.context = Some(ExecutionContext::System);
+            }
+            ("context", "user") => {
+                entry.context = Some(ExecutionContext::User);
+            }
+            ("reboot", "enabled") => {
+                entry.reboot_required = true;
+            }
+            ("reboot", "disabled") => {
+                entry.reboot_required = false;
+            }
+            ("type", "oneshot") => {
+                entry.tasktype = Some(TaskType::OneShot);
+            }
+            ("type", "onboot") => {
+                entry.tasktype = Some(TaskType::OnBoot);
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Applies the settings of a task manifest, they take effect like the rules of another folder.
+    fn apply_manifest(
+        entry: &mut StackEntry,
+        manifest: &TaskManifest,
+        group_aliases: &HashMap<String, String>,
+    ) -> Result<(), RuleError> {
+        if let Some(tasktype) = &manifest.type_ {
+            entry.tasktype = Some(tasktype.clone());
+        }
+        if let Some(context) = &manifest.context {
+            entry.context = Some(context.clone());
+        }
+        if let Some(reboot) = manifest.reboot {
+            entry.reboot_required = reboot;
+        }
+
+        for (kind, values) in [
+            ("group", &manifest.groups),
+            ("groupid", &manifest.group_ids),
+            ("user", &manifest.users),
+            ("host", &manifest.hosts),
+            ("os", &manifest.os),
+            ("arch", &manifest.arch),
+            ("depends", &manifest.depends),
+        ] {
+            if !values.is_empty() {
+                let values = values.iter().map(|v| literal_value(v)).collect();
+                Self::apply_rule_values(entry, kind, values, group_aliases)?;
+            }
+        }
+
+        for (attribute, values) in &manifest.attributes {
+            let values = values.iter().map(|v| literal_value(v)).collect();
+            Self::apply_attr_values(entry, attribute.clone(), values);
+        }
+
+        Ok(())
+    }
+
+    fn apply_attr_values(entry: &mut StackEntry, attribute: String, values: Vec<RuleValue>) {
+        let filter = entry
+            .attr_filter
+            .get_or_insert_with(HashMap::new)
+            .entry(attribute)
+            .or_default();
+
+        for v in values {
+            filter.insert(v.negated, v.value);
+        }
+    }
+
+    /// Applies the values of a `group`, `groupid`, `user`, `host`, `os`, `arch`, `depends` or `reset` rule.
+    fn apply_rule_values(
+        entry: &mut StackEntry,
+        kind: &str,
+        values: Vec<RuleValue>,
+        group_aliases: &HashMap<String, String>,
+    ) -> Result<(), RuleError> {
+        match kind {
+            "group" => {
+                let filter = entry.group_filter.get_or_insert_with(Filter::default);
+
+                for v in values {
+                    let name = v.value.to_lowercase();
+                    let group = match group_aliases.get(&name) {
+                        Some(id) => GroupRef::Id(id.clone()),
+                        None => GroupRef::Name(name),
+                    };
+                    filter.insert(v.negated, group);
+                }
+            }
+            "groupid" => {
+                let filter = entry.group_filter.get_or_insert_with(Filter::default);
+
+                for v in values {
+                    filter.insert(v.negated, GroupRef::Id(v.value.to_lowercase()));
+                }
+            }
+            "user" => {
+                let filter = entry.user_filter.get_or_insert_with(Filter::default);
+
+                for v in values {
+                    filter.insert(v.negated, v.value);
+                }
+            }
+            "host" | "os" | "arch" => {
+                let mut filter = Filter::default();
+
+                for v in values {
+                    let rule = DeviceRule::parse(kind, &v.value)
+                        .ok_or_else(|| RuleError::InvalidValue(v.value.clone()))?;
+                    filter.insert(v.negated, rule);
+                }
+
+                entry
+                    .device_filter
+                    .get_or_insert_with(Vec::new)
+                    .push(filter);
+            }
+            "depends" => {
+                if let Some(v) = values.iter().find(|v| v.negated) {
+                    return Err(RuleError::InvalidValue(format!("!{}", v.value)));
+                }
+
+                entry
+                    .depends_on
+                    .get_or_insert_with(HashSet::new)
+                    .extend(values.into_iter().map(|v| v.value));
+            }
+            "reset" => {
+                for v in values {
+                    match (v.negated, v.value.to_lowercase().as_str()) {
+                        (false, "group" | "groupid") => entry.group_filter = None,
+                        (false, "user") => entry.user_filter = None,
+                        (false, "attr") => entry.attr_filter = None,
+                        (false, kind @ ("host" | "os" | "arch")) => {
+                            if let Some(device_filter) = &mut entry.device_filter {
+                                device_filter.retain(|f| {
+                                    f.include.iter().chain(&f.exclude).all(|r| r.kind() != kind)
+                                });
+                            }
+                        }
+                        (false, "depends") => entry.depends_on = None,
+                        (false, "all") => {
+                            entry.group_filter = None;
+                            entry.user_filter = None;
+                            entry.attr_filter = None;
+                            entry.device_filter = None;
+                            entry.depends_on = None;
+                        }
+                        _ => return Err(RuleError::InvalidValue(v.value)),
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
     pub fn build_tasks_from_directory(
         dir: &Path,
         wanted_execution_context: ExecutionContext,
         upn: Option<String>,
-    ) -> Option<Tasks> {
-        let user_group_membership: Option<HashSet<String>> = match &upn {
-            Some(u) => get_entra_groups_of_user(u).ok(),
-            None => None,
-        };
+        device: &dyn DeviceFactsProvider,
+        config: &Config,
+    ) -> Result<(Tasks, FetchReport), Box<dyn Error>> {
+        let repo_manifest = manifest::load_repo(dir)?;
+        info!("repository manifest: {:?}", repo_manifest);
+        let group_aliases = Self::load_group_aliases(dir)?;
+        let device_facts = device.device_facts();
 
-        info!(
-            "user_group_membership of {:?}:{:?}",
-            &upn, user_group_membership
-        );
+        info!("device facts: {:?}", device_facts);
+        let mut report = FetchReport::default();
+
+        let groups = LazyGroupMembership {
+            wanted_execution_context: &wanted_execution_context,
+            upn: upn.as_deref(),
+            config,
+            resolved: OnceCell::new(),
+        };
 
         let mut tasks: Vec<Task> = Vec::new();
+        // the attributes are looked up at once after all rules are known
+        let mut attr_restricted_tasks: Vec<(Task, AttributeFilter)> = Vec::new();
+        // the tasks of this context and their references before the filters,
+        // so that the filters do not change what a reference resolves to
+        let mut tasks_by_name: HashMap<String, Vec<String>> = HashMap::new();
+        // the tasks of the other context and those with an invalid manifest or in a folder with an
+        // invalid rule, dependencies on them
+        // are handled like dependencies on tasks left out by a rule; the notes tell why
+        let mut left_out_tasks_by_name: HashMap<String, Vec<String>> = HashMap::new();
+        let mut left_out_notes: HashMap<String, String> = HashMap::new();
+        let mut dependency_references: HashMap<String, Vec<String>> = HashMap::new();
 
         let mut stack: Vec<StackEntry> = Vec::new();
         stack.push(StackEntry {
             path: dir.to_path_buf(),
-            tasktype: None,
-            context: None,
+            tasktype: repo_manifest.defaults.type_.clone(),
+            context: repo_manifest.defaults.context.clone(),
             depends_on: None,
             user_filter: None,
             group_filter: None,
-            reboot_required: false,
+            attr_filter: None,
+            device_filter: None,
+            reboot_required: repo_manifest.defaults.reboot,
         });
 
         while !stack.is_empty() {
             let mut entry = stack.pop().unwrap();
 
             if entry.path.is_file() {
+                match ScriptKind::from_path(entry.path.as_path()) {
+                    Some(kind) if kind == ScriptKind::native() => (),
+                    Some(kind) => {
+                        info!(
+                            "Skipping {}, since it is the {:?} variant for another platform",
+                            entry.path.as_path().display(),
+                            kind
+                        );
+                        continue;
+                    }
+                    None => {
+                        info!(
+                            "Skipping {}, since it is not a script",
+                            entry.path.as_path().display()
+                        );
+                        continue;
+                    }
+                }
+
+                let manifest = manifest::load(&entry.path)
+                    .map(Option::unwrap_or_default)
+                    .and_then(|manifest| {
+                        Self::apply_manifest(&mut entry, &manifest, &group_aliases)
+                            .map_err(|e| format!("invalid manifest: {}", e))?;
+                        Ok(manifest)
+                    });
+
+                let manifest = match manifest {
+                    Ok(manifest) => manifest,
+                    Err(e) => {
+                        warn!("Skipping {}: {}", entry.path.display(), e);
+                        Self::register_left_out(
+                            &mut left_out_tasks_by_name,
+                            &mut left_out_notes,
+                            dir,
+                            &entry.path,
+                            repo_manifest.priority_prefixes,
+                            "invalid manifest".to_string(),
+                        );
+                        report.invalid.push(InvalidEntry {
+                            path: Self::relative_path(dir, &entry.path),
+                            error: e.to_string(),
+                        });
+                        continue;
+                    }
+                };
+
                 match &entry.context {
                     None => {
                         info!("context is None, skipping {:?}", entry);
@@ -128,6 +868,16 @@ impl TaskFetcher {
                             "Execution context is not {:?}, skipping {:?}",
                             wanted_execution_context, entry
                         );
+                        if entry.tasktype.is_some() {
+                            Self::register_left_out(
+                                &mut left_out_tasks_by_name,
+                                &mut left_out_notes,
+                                dir,
+                                &entry.path,
+                                repo_manifest.priority_prefixes,
+                                "other execution context".to_string(),
+                            );
+                        }
                         continue;
                     }
                     _ => (),
@@ -137,38 +887,70 @@ impl TaskFetcher {
                     info!("tasktype is None, skipping {:?}", entry);
                     continue;
                 }
-                if let Some(e) = entry.path.as_path().extension() {
-                    if e != "ps1" {
-                        info!(
-                            "Skipping {}, since it is not a .ps1",
-                            entry.path.as_path().display()
-                        );
+
+                let id = Self::task_id(dir, &entry.path);
+
+                for name in Self::dependency_names(&entry.path, repo_manifest.priority_prefixes) {
+                    tasks_by_name.entry(name).or_default().push(id.clone());
+                }
+                for reference in entry.depends_on.iter().flatten() {
+                    dependency_references
+                        .entry(reference.clone())
+                        .or_default()
+                        .push(id.clone());
+                }
+
+                if let Some(device_filter) = &entry.device_filter {
+                    if let Some(rule) = device_filter
+                        .iter()
+                        .find(|f| !f.matches(|r| r.matches(&device_facts)))
+                    {
+                        let reason = format!("the device does not match {:?}", rule);
+                        info!("Skipping {}, since {}", entry.path.display(), reason);
+                        report.skipped.push(SkippedTask {
+                            id: Self::task_id(dir, &entry.path),
+                            reason,
+                        });
+                        continue;
+                    }
+                }
+
+                if let Some(user_filter) = &entry.user_filter {
+                    if let Some(reason) = Self::user_filter_mismatch(user_filter, upn.as_deref()) {
+                        info!("Skipping {}, since {}", entry.path.display(), reason);
+                        report.skipped.push(SkippedTask {
+                            id: Self::task_id(dir, &entry.path),
+                            reason,
+                        });
                         continue;
                     }
-                } else {
-                    info!(
-                        "Skipping {}, since it has no extension",
-                        entry.path.as_path().display()
-                    );
-                    continue;
                 }
 
-                if matches!(entry.context.as_ref().unwrap(), ExecutionContext::User)
-                    && entry.group_filter.is_some()
-                {
-                    if let Some(user_group_membership) = &user_group_membership {
-                        if !entry
-                            .group_filter
-                            .as_ref()
-                            .unwrap()
-                            .iter()
-                            .any(|req_group| user_group_membership.contains(req_group))
+                if let Some(group_filter) = &entry.group_filter {
+                    let resolved = groups.get()?;
+                    let reason = match &resolved.membership {
+                        Some(group_membership)
+                            if group_filter.matches(|group| group_membership.contains(group)) =>
                         {
-                            info!("Skipping {:?}, since the user is in none of the required groups ({:?})", &entry, user_group_membership);
-                            continue;
+                            None
                         }
-                    } else {
-                        info!("Skipping {:?}, since the user is in none of the required groups (no group membership)", &entry);
+                        Some(_) => Some(format!(
+                            "the {} does not match the group rules {:?}",
+                            resolved.principal.as_ref().unwrap(),
+                            group_filter
+                        )),
+                        None => Some(match &resolved.principal {
+                            Some(p) => format!("no group membership of the {} is known", p),
+                            None => "no UPN or device id is known for the group rules".into(),
+                        }),
+                    };
+
+                    if let Some(reason) = reason {
+                        info!("Skipping {}, since {}", entry.path.display(), reason);
+                        report.skipped.push(SkippedTask {
+                            id: Self::task_id(dir, &entry.path),
+                            reason,
+                        });
                         continue;
                     }
                 }
@@ -179,96 +961,137 @@ impl TaskFetcher {
                     .digest()
                     .expect("Failed to calculate hash");
 
-                let task_name = entry
-                    .path
-                    .as_path()
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .trim_end_matches(".ps1")
-                    .to_string();
-
-                tasks.push(Task {
+                let priority = manifest
+                    .priority
+                    .or_else(|| {
+                        if !repo_manifest.priority_prefixes {
+                            return None;
+                        }
+                        let stem = entry.path.file_stem()?.to_str()?;
+                        Some(Self::priority_prefix(stem)?.0)
+                    })
+                    .or(repo_manifest.defaults.priority)
+                    .unwrap_or(DEFAULT_PRIORITY);
+
+                let task = Task {
                     type_: entry.tasktype.as_ref().unwrap().clone(),
-                    name: task_name,
+                    id: Self::task_id(dir, &entry.path),
+                    name: Self::task_name(&entry.path),
                     context: entry.context.as_ref().unwrap().clone(),
                     depends_on: entry.depends_on,
-                    user_filter: entry.user_filter,
-                    group_filter: entry.group_filter,
+                    user_filter: entry.user_filter.map(|f| f.include),
+                    group_filter: entry.group_filter.map(|f| f.include),
                     executable: entry.path,
                     reboot_required: entry.reboot_required,
                     hash: digest,
-                })
+                    arguments: manifest.arguments,
+                    timeout_secs: manifest
+                        .timeout_secs
+                        .or(repo_manifest.defaults.timeout_secs),
+                    success_codes: manifest
+                        .success_codes
+                        .or_else(|| repo_manifest.defaults.success_codes.clone())
+                        .unwrap_or_else(|| vec![0]),
+                    priority,
+                };
+
+                match entry.attr_filter {
+                    Some(attr_filter) => attr_restricted_tasks.push((task, attr_filter)),
+                    None => tasks.push(task),
+                }
             } else if entry.path.is_dir() {
                 match fs::read_dir(entry.path.as_path()) {
-                    Ok(dir) => {
+                    Ok(entries) => {
                         if let Some(sn) = entry.path.as_path().file_name() {
-                            let sn = sn.to_str().unwrap();
-                            let mut spl = sn.splitn(2, "-");
-                            match (spl.next(), spl.next()) {
-                                (Some("group"), Some(name)) => {
-                                    if entry.group_filter.is_none() {
-                                        entry.group_filter = Some(HashSet::new());
-                                    }
-                                    entry
-                                        .group_filter
-                                        .as_mut()
-                                        .unwrap()
-                                        .insert(name.to_string());
-                                }
-                                (Some("user"), Some(name)) => {
-                                    if entry.user_filter.is_none() {
-                                        entry.user_filter = Some(HashSet::new());
-                                    }
-                                    entry.user_filter.as_mut().unwrap().insert(name.to_string());
-                                }
-                                (Some("depends"), Some(name)) => {
-                                    if entry.depends_on.is_none() {
-                                        entry.depends_on = Some(HashSet::new());
-                                    }
-                                    entry.depends_on.as_mut().unwrap().insert(name.to_string());
-                                }
-                                (Some("context"), Some("system")) => {
-                                    entry.context = Some(ExecutionContext::System);
-                                }
-                                (Some("context"), Some("user")) => {
-                                    entry.context = Some(ExecutionContext::User);
-                                }
-                                (Some("reboot"), Some("enabled")) => {
-                                    entry.reboot_required = true;
-                                }
-                                (Some("reboot"), Some("disabled")) => {
-                                    entry.reboot_required = false;
-                                }
-                                (Some("type"), Some("oneshot")) => {
-                                    entry.tasktype = Some(TaskType::OneShot);
-                                }
-                                (Some("type"), Some("onboot")) => {
-                                    entry.tasktype = Some(TaskType::OnBoot);
+                            let sn = sn.to_str().unwrap().to_string();
+
+                            // a broken restriction must not widen the targeting
+                            if let Err(e) = Self::apply_folder_rule(&mut entry, &sn, &group_aliases)
+                            {
+                                warn!(
+                                    "Skipping {}, since its rule is invalid: {}",
+                                    entry.path.display(),
+                                    e
+                                );
+                                let folder = Self::relative_path(dir, &entry.path);
+                                for path in
+                                    Self::native_scripts(dir, &entry.path, &repo_manifest.ignore)
+                                {
+                                    Self::register_left_out(
+                                        &mut left_out_tasks_by_name,
+                                        &mut left_out_notes,
+                                        dir,
+                                        &path,
+                                        repo_manifest.priority_prefixes,
+                                        format!("in {}, whose rule is invalid", folder),
+                                    );
                                 }
-                                (_, _) => (),
+                                report.invalid.push(InvalidEntry {
+                                    path: folder,
+                                    error: format!("invalid rule: {}", e),
+                                });
+                                continue;
                             }
                         }
 
-                        for e in dir {
+                        // stems of the tasks in this directory which have a native variant,
+                        // and the variants of tasks which only exist for other platforms
+                        let mut native_variants: HashSet<OsString> = HashSet::new();
+                        let mut foreign_variants: HashMap<OsString, PathBuf> = HashMap::new();
+
+                        let mut paths: Vec<PathBuf> = Vec::new();
+
+                        for e in entries {
                             match e {
-                                Ok(e) => {
-                                    stack.push(StackEntry {
-                                        path: e.path().to_path_buf(),
-                                        tasktype: entry.tasktype.clone(),
-                                        context: entry.context.clone(),
-                                        depends_on: entry.depends_on.clone(),
-                                        user_filter: entry.user_filter.clone(),
-                                        group_filter: entry.group_filter.clone(),
-                                        reboot_required: entry.reboot_required,
-                                    });
-                                }
+                                Ok(e) => paths.push(e.path()),
                                 Err(err) => {
                                     info!("Error reading directory entry: {:?}", err);
                                 }
                             }
                         }
+
+                        // sorted, since the order of read_dir depends on the file system,
+                        // and pushed in reverse to be popped in order
+                        paths.sort();
+
+                        for path in paths.into_iter().rev() {
+                            if Self::is_ignored(dir, &path, &repo_manifest.ignore) {
+                                info!("Skipping {}, since it is ignored", path.display());
+                                continue;
+                            }
+
+                            if let (Some(kind), Some(stem)) =
+                                (ScriptKind::from_path(&path), path.file_stem())
+                            {
+                                if kind == ScriptKind::native() {
+                                    native_variants.insert(stem.to_os_string());
+                                } else {
+                                    foreign_variants.insert(stem.to_os_string(), path.clone());
+                                }
+                            }
+
+                            stack.push(StackEntry {
+                                path,
+                                tasktype: entry.tasktype.clone(),
+                                context: entry.context.clone(),
+                                depends_on: entry.depends_on.clone(),
+                                user_filter: entry.user_filter.clone(),
+                                group_filter: entry.group_filter.clone(),
+                                attr_filter: entry.attr_filter.clone(),
+                                device_filter: entry.device_filter.clone(),
+                                reboot_required: entry.reboot_required,
+                            });
+                        }
+
+                        for (stem, path) in foreign_variants {
+                            if !native_variants.contains(&stem) {
+                                warn!(
+                                    "Task {:?} has no variant for this platform, only {}",
+                                    stem,
+                                    path.display()
+                                );
+                            }
+                        }
                     }
                     Err(e) => {
                         info!("Error listing directoriy entries: {:?}", e);
@@ -277,51 +1100,722 @@ impl TaskFetcher {
             }
         }
 
-        if let Some(ordered_tasks) = Self::order_tasks_by_dependency(&tasks) {
-            Some(Tasks(ordered_tasks))
-        } else {
-            None
+        report.group_membership = groups.source();
+
+        let dependencies = Self::resolve_dependencies(
+            &dependency_references,
+            &tasks_by_name,
+            &left_out_tasks_by_name,
+        )?;
+
+        if !attr_restricted_tasks.is_empty() {
+            let attributes: HashSet<String> = attr_restricted_tasks
+                .iter()
+                .flat_map(|(_, attr_filter)| attr_filter.keys().cloned())
+                .collect();
+            let user_attributes = Self::resolve_user_attributes(
+                &wanted_execution_context,
+                upn.as_deref(),
+                &attributes,
+                config,
+            )?;
+
+            info!("user attributes of {:?}: {:?}", &upn, user_attributes);
+
+            for (task, attr_filter) in attr_restricted_tasks {
+                match Self::attr_filter_mismatch(&attr_filter, user_attributes.as_ref()) {
+                    Some(reason) => {
+                        info!("Skipping {}, since {}", task.executable.display(), reason);
+                        report.skipped.push(SkippedTask {
+                            id: task.id,
+                            reason,
+                        });
+                    }
+                    None => tasks.push(task),
+                }
+            }
         }
+
+        for task in tasks.iter_mut() {
+            if let Some(depends_on) = &task.depends_on {
+                task.depends_on = Some(
+                    depends_on
+                        .iter()
+                        .map(|reference| dependencies[reference].clone())
+                        .collect(),
+                );
+            }
+        }
+
+        let tasks = Self::apply_filtered_dependency_policy(
+            tasks,
+            &left_out_notes,
+            &repo_manifest.filtered_dependency_policy,
+            &mut report,
+        )?;
+
+        Ok((Tasks(Self::order_tasks_by_dependency(tasks)?), report))
     }
 
-    fn order_tasks_by_dependency(orig_tasks: &[Task]) -> Option<Vec<Task>> {
-        let mut ordered_tasks: HashSet<String> = HashSet::new();
-        let mut unordered_tasks: VecDeque<usize> = VecDeque::new();
+    /// Handles the tasks depending on tasks which are left out by a rule or before their rules are
+    /// known (`left_out_notes`: the id -> why), until no such task is left.
+    fn apply_filtered_dependency_policy(
+        mut tasks: Vec<Task>,
+        left_out_notes: &HashMap<String, String>,
+        policy: &FilteredDependencyPolicy,
+        report: &mut FetchReport,
+    ) -> Result<Vec<Task>, TaskFetchterError> {
+        loop {
+            let selected: HashSet<String> = tasks.iter().map(|t| t.id.clone()).collect();
+            let mut left_out = false;
+            let mut kept = Vec::with_capacity(tasks.len());
+
+            for mut task in tasks {
+                let mut filtered: Vec<String> = task
+                    .depends_on
+                    .iter()
+                    .flatten()
+                    .filter(|d| !selected.contains(*d))
+                    .map(|d| match left_out_notes.get(d) {
+                        Some(note) => format!("{} ({})", d, note),
+                        None => d.clone(),
+                    })
+                    .collect();
+                filtered.sort();
+
+                if filtered.is_empty() {
+                    kept.push(task);
+                    continue;
+                }
+
+                match policy {
+                    FilteredDependencyPolicy::Run => {
+                        info!(
+                            "Running {} without its dependencies {}, since they are left out",
+                            task.id,
+                            filtered.join(", ")
+                        );
+                        if let Some(depends_on) = task.depends_on.as_mut() {
+                            depends_on.retain(|d| selected.contains(d));
+                        }
+                        kept.push(task);
+                    }
+                    FilteredDependencyPolicy::Abort => {
+                        return Err(TaskFetchterError::FilteredDependency {
+                            task: task.id,
+                            dependency: filtered.remove(0),
+                        });
+                    }
+                    FilteredDependencyPolicy::Skip => {
+                        let reason =
+                            format!("it depends on {}, which is left out", filtered.join(", "));
+                        info!("Skipping {}, since {}", task.executable.display(), reason);
+                        report.skipped.push(SkippedTask {
+                            id: task.id,
+                            reason,
+                        });
+                        left_out = true;
+                    }
+                }
+            }
+
+            tasks = kept;
 
-        for i in 0..orig_tasks.len() {
-            unordered_tasks.push_back(i);
+            if !left_out {
+                return Ok(tasks);
+            }
         }
+    }
 
-        let mut iteration_count = 0;
-        let mut tasks = Vec::new();
+    /// Orders the tasks so that every task comes after its dependencies (Kahn's algorithm).
+    /// Of the tasks whose dependencies have run, the one with the lowest priority and then id runs first.
+    fn order_tasks_by_dependency(tasks: Vec<Task>) -> Result<Vec<Task>, TaskFetchterError> {
+        let index: HashMap<&str, usize> = tasks
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.id.as_str(), i))
+            .collect();
+        // number of dependencies not ordered yet and the tasks depending on each task
+        let mut pending = vec![0; tasks.len()];
+        let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); tasks.len()];
 
-        'outer: while !unordered_tasks.is_empty() {
-            info!("Ordered_tasks: {:?}", ordered_tasks);
-            info!("Unordered_tasks: {:?}", unordered_tasks);
+        for (i, task) in tasks.iter().enumerate() {
+            for dep in task.depends_on.iter().flatten() {
+                if let Some(&d) = index.get(dep.as_str()) {
+                    pending[i] += 1;
+                    dependents[d].push(i);
+                }
+            }
+        }
 
-            let c_task = unordered_tasks.pop_front().unwrap();
+        let key = |i: usize| Reverse((tasks[i].priority, tasks[i].id.as_str(), i));
+        let mut ready: BinaryHeap<_> = (0..tasks.len())
+            .filter(|&i| pending[i] == 0)
+            .map(key)
+            .collect();
+        let mut order = Vec::with_capacity(tasks.len());
 
-            info!("Got task: {:?}", orig_tasks[c_task]);
-            if let Some(dependencies) = orig_tasks[c_task].depends_on.as_ref() {
-                for dep in dependencies {
-                    if !ordered_tasks.contains(dep) {
-                        if iteration_count >= unordered_tasks.len() {
-                            info!("Circular dependency detected, giving up!");
-                            return None;
-                        }
+        while let Some(Reverse((_, _, i))) = ready.pop() {
+            order.push(i);
 
-                        iteration_count += 1;
-                        unordered_tasks.push_back(c_task);
-                        continue 'outer;
-                    }
+            for &j in &dependents[i] {
+                pending[j] -= 1;
+                if pending[j] == 0 {
+                    ready.push(key(j));
                 }
             }
+        }
+
+        if order.len() < tasks.len() {
+            let cycle = Self::find_cycle(&tasks, &index, &pending);
+            info!("Circular dependency detected: {:?}", cycle);
+            return Err(TaskFetchterError::Cycle(cycle));
+        }
+
+        let mut tasks: Vec<Option<Task>> = tasks.into_iter().map(Some).collect();
+
+        Ok(order
+            .into_iter()
+            .map(|i| tasks[i].take().unwrap())
+            .collect())
+    }
+
+    /// Follows the dependencies among the tasks which could not be ordered until a task repeats.
+    /// Each of them has a dependency which could not be ordered either.
+    fn find_cycle(tasks: &[Task], index: &HashMap<&str, usize>, pending: &[usize]) -> Vec<String> {
+        let mut path: Vec<usize> = Vec::new();
+        let mut current = (0..tasks.len()).find(|&i| pending[i] > 0).unwrap();
+
+        while !path.contains(&current) {
+            path.push(current);
+            current = tasks[current]
+                .depends_on
+                .iter()
+                .flatten()
+                .filter_map(|dep| index.get(dep.as_str()).copied())
+                .filter(|&d| pending[d] > 0)
+                .min_by_key(|&d| &tasks[d].id)
+                .unwrap();
+        }
+
+        let start = path.iter().position(|&i| i == current).unwrap();
+
+        path[start..]
+            .iter()
+            .chain(std::iter::once(&current))
+            .map(|&i| tasks[i].id.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::IdentityConfig,
+        device::{Arch, DeviceFacts, OsFamily, StaticDeviceFactsProvider},
+        identity::{Identity, IdentityProvider, MappedIdentityProvider, StaticIdentityProvider},
+    };
+
+    /// Creates a repository in the temp directory with a script at each of the relative paths,
+    /// the extension of the scripts of this platform is appended.
+    fn repo(name: &str, scripts: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "repo_task_run_test_{}_{}",
+            name,
+            std::process::id()
+        ));
+        let extension = match ScriptKind::native() {
+            ScriptKind::PowerShell => "ps1",
+            ScriptKind::Shell => "sh",
+        };
+
+        fs::remove_dir_all(&dir).ok();
+
+        for (path, content) in scripts {
+            let path = dir.join(format!("{}.{}", path, extension));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
 
-            iteration_count = 0;
-            ordered_tasks.insert(orig_tasks[c_task].name.clone());
-            tasks.push(orig_tasks[c_task].clone());
+        dir
+    }
+
+    fn windows_kiosk(build: u32) -> StaticDeviceFactsProvider {
+        StaticDeviceFactsProvider(DeviceFacts {
+            hostname: "KIOSK-01".to_string(),
+            os: OsFamily::Windows,
+            os_build: Some(build),
+            arch: Arch::X64,
+        })
+    }
+
+    /// Ids of the tasks of the system context in execution order.
+    fn task_ids(dir: &Path, device: &dyn DeviceFactsProvider) -> Vec<String> {
+        let (tasks, _) = TaskFetcher::build_tasks_from_directory(
+            dir,
+            ExecutionContext::System,
+            None,
+            device,
+            &Config::default(),
+        )
+        .unwrap();
+
+        tasks.0.into_iter().map(|t| t.id).collect()
+    }
+
+    fn load(
+        dir: &Path,
+        context: ExecutionContext,
+    ) -> Result<(Vec<String>, FetchReport), Box<dyn Error>> {
+        let (tasks, report) = TaskFetcher::build_tasks_from_directory(
+            dir,
+            context,
+            None,
+            &windows_kiosk(22631),
+            &Config::default(),
+        )?;
+
+        Ok((tasks.0.into_iter().map(|t| t.id).collect(), report))
+    }
+
+    #[test]
+    fn dependencies_on_the_other_context_follow_the_policy() {
+        let dir = repo(
+            "other_context",
+            &[
+                ("type-oneshot/context-system/install", ""),
+                ("type-oneshot/context-user/configure", ""),
+                ("type-oneshot/context-user/depends-install/post", ""),
+            ],
+        );
+
+        let (ids, report) = load(&dir, ExecutionContext::User).unwrap();
+        assert_eq!(ids, ["type-oneshot/context-user/configure"]);
+        assert_eq!(
+            report.skipped[0].id,
+            "type-oneshot/context-user/depends-install/post"
+        );
+        assert!(report.skipped[0]
+            .reason
+            .contains("type-oneshot/context-system/install (other execution context)"));
+
+        fs::write(
+            dir.join(manifest::REPO_MANIFEST_FILE_NAME),
+            "format_version = 1\nfiltered_dependency_policy = \"run\"\n",
+        )
+        .unwrap();
+        let (ids, _) = load(&dir, ExecutionContext::User).unwrap();
+        assert_eq!(ids.len(), 2);
+
+        fs::write(
+            dir.join(manifest::REPO_MANIFEST_FILE_NAME),
+            "format_version = 1\nfiltered_dependency_policy = \"abort\"\n",
+        )
+        .unwrap();
+        let e = load(&dir, ExecutionContext::User).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<TaskFetchterError>(),
+            Some(TaskFetchterError::FilteredDependency { .. })
+        ));
+
+        // the system context is not affected by the user tasks
+        fs::remove_file(dir.join(manifest::REPO_MANIFEST_FILE_NAME)).unwrap();
+        let (ids, _) = load(&dir, ExecutionContext::System).unwrap();
+        assert_eq!(ids, ["type-oneshot/context-system/install"]);
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn dependencies_on_no_task_are_errors() {
+        let dir = repo(
+            "missing_dependency",
+            &[("type-oneshot/context-user/depends-nothing/post", "")],
+        );
+
+        let e = load(&dir, ExecutionContext::User).unwrap_err();
+        match e.downcast_ref::<TaskFetchterError>() {
+            Some(TaskFetchterError::MissingDependencies(missing)) => assert_eq!(
+                missing,
+                &[(
+                    "type-oneshot/context-user/depends-nothing/post".to_string(),
+                    "nothing".to_string()
+                )]
+            ),
+            other => panic!("{:?}", other),
         }
 
-        Some(tasks)
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn invalid_rules_and_manifests_are_reported() {
+        let dir = repo(
+            "invalid_entries",
+            &[
+                ("type-oneshot/context-system/group-bad%zz/base", ""),
+                ("type-oneshot/context-system/broken", ""),
+                ("type-oneshot/context-system/fine", ""),
+            ],
+        );
+        fs::write(
+            dir.join("type-oneshot/context-system/broken.toml"),
+            "nonsense = [",
+        )
+        .unwrap();
+
+        let (ids, report) = load(&dir, ExecutionContext::System).unwrap();
+        assert_eq!(ids, ["type-oneshot/context-system/fine"]);
+
+        let mut invalid: Vec<(&str, &str)> = report
+            .invalid
+            .iter()
+            .map(|i| (i.path.as_str(), i.error.as_str()))
+            .collect();
+        invalid.sort();
+        assert_eq!(invalid.len(), 2);
+        assert!(invalid[0]
+            .0
+            .starts_with("type-oneshot/context-system/broken."));
+        assert_eq!(invalid[1].0, "type-oneshot/context-system/group-bad%zz");
+        assert!(invalid[1].1.starts_with("invalid rule"));
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn dependencies_on_tasks_with_an_invalid_manifest_follow_the_policy() {
+        let dir = repo(
+            "invalid_manifest_dependency",
+            &[
+                ("type-oneshot/context-system/base", ""),
+                ("type-oneshot/context-system/depends-base/post", ""),
+            ],
+        );
+        fs::write(
+            dir.join("type-oneshot/context-system/base.toml"),
+            "nonsense = [",
+        )
+        .unwrap();
+
+        let (ids, report) = load(&dir, ExecutionContext::System).unwrap();
+        assert!(ids.is_empty());
+        assert_eq!(
+            report.skipped[0].id,
+            "type-oneshot/context-system/depends-base/post"
+        );
+        assert!(report.skipped[0]
+            .reason
+            .contains("type-oneshot/context-system/base (invalid manifest)"));
+        assert_eq!(report.invalid.len(), 1);
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn dependencies_into_folders_with_an_invalid_rule_name_the_folder() {
+        let dir = repo(
+            "invalid_rule_dependency",
+            &[
+                ("type-oneshot/context-system/group-bad%zz/base", ""),
+                ("type-oneshot/context-system/depends-base/post", ""),
+            ],
+        );
+
+        let (ids, report) = load(&dir, ExecutionContext::System).unwrap();
+        assert!(ids.is_empty());
+        assert!(report.skipped[0].reason.contains(
+            "type-oneshot/context-system/group-bad%zz/base \
+             (in type-oneshot/context-system/group-bad%zz, whose rule is invalid)"
+        ));
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn ignore_patterns_match_names_and_paths() {
+        let root = Path::new("/repo");
+        let ignore = [
+            "drafts".to_string(),
+            "*.wip.ps1".into(),
+            "legacy/old-*".into(),
+        ];
+        let ignored = |path: &str| TaskFetcher::is_ignored(root, &root.join(path), &ignore);
+
+        assert!(ignored("drafts"));
+        assert!(ignored("type-oneshot/drafts"));
+        assert!(ignored("type-oneshot/setup.WIP.ps1"));
+        assert!(ignored("legacy/old-setup.ps1"));
+        assert!(!ignored("type-oneshot/legacy/old-setup.ps1"));
+        assert!(!ignored("legacy/setup.ps1"));
+        assert!(!ignored("type-oneshot/drafts.ps1"));
+    }
+
+    #[test]
+    fn repo_manifest_defaults_and_ignore_apply() {
+        let dir = repo(
+            "repo_manifest",
+            &[
+                ("setup", ""),
+                ("drafts/next", ""),
+                ("legacy/old-setup", ""),
+                ("legacy/setup", ""),
+                ("context-user/configure", ""),
+            ],
+        );
+        fs::write(
+            dir.join(manifest::REPO_MANIFEST_FILE_NAME),
+            "format_version = 1\nignore = [\"drafts\", \"legacy/old-*\"]\n\n\
+             [defaults]\ncontext = \"system\"\ntype = \"oneshot\"\n",
+        )
+        .unwrap();
+
+        // scripts in the root and in folders without rules use the defaults
+        let (ids, _) = load(&dir, ExecutionContext::System).unwrap();
+        assert_eq!(ids, ["legacy/setup", "setup"]);
+
+        let (ids, _) = load(&dir, ExecutionContext::User).unwrap();
+        assert_eq!(ids, ["context-user/configure"]);
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn file_names_keep_their_numeric_prefix() {
+        let dir = repo(
+            "numeric_prefix",
+            &[
+                ("type-oneshot/context-system/7-zip", ""),
+                ("type-oneshot/context-system/depends-7-zip/install", ""),
+            ],
+        );
+
+        let (ids, _) = load(&dir, ExecutionContext::System).unwrap();
+        assert_eq!(
+            ids,
+            [
+                "type-oneshot/context-system/7-zip",
+                "type-oneshot/context-system/depends-7-zip/install"
+            ]
+        );
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn priority_prefixes_are_opt_in() {
+        let dir = repo(
+            "priority_prefixes",
+            &[
+                ("type-oneshot/context-system/60-late", ""),
+                ("type-oneshot/context-system/early", ""),
+            ],
+        );
+
+        let (ids, _) = load(&dir, ExecutionContext::System).unwrap();
+        assert_eq!(
+            ids,
+            [
+                "type-oneshot/context-system/60-late",
+                "type-oneshot/context-system/early"
+            ]
+        );
+
+        fs::remove_dir_all(dir).ok();
+
+        let dir = repo(
+            "priority_prefixes_opt_in",
+            &[
+                ("type-oneshot/context-system/60-late", ""),
+                ("type-oneshot/context-system/early", ""),
+                ("type-oneshot/context-system/depends-late/post", ""),
+            ],
+        );
+        fs::write(
+            dir.join(manifest::REPO_MANIFEST_FILE_NAME),
+            "format_version = 1\npriority_prefixes = true\n",
+        )
+        .unwrap();
+
+        let (ids, _) = load(&dir, ExecutionContext::System).unwrap();
+        assert_eq!(
+            ids,
+            [
+                "type-oneshot/context-system/early",
+                "type-oneshot/context-system/60-late",
+                "type-oneshot/context-system/depends-late/post"
+            ]
+        );
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn device_rules_select_tasks() {
+        let dir = repo(
+            "device_rules",
+            &[
+                ("context-system/type-oneshot/os-win11/a", ""),
+                ("context-system/type-oneshot/os-win10/b", ""),
+                (
+                    "context-system/type-oneshot/host-KIOSK-%2A/os-build-lt-23000/c",
+                    "",
+                ),
+                ("context-system/type-oneshot/arch-arm64/d", ""),
+            ],
+        );
+
+        assert_eq!(
+            task_ids(&dir, &windows_kiosk(22631)),
+            [
+                "context-system/type-oneshot/host-KIOSK-%2A/os-build-lt-23000/c",
+                "context-system/type-oneshot/os-win11/a",
+            ]
+        );
+        assert_eq!(
+            task_ids(&dir, &windows_kiosk(19045)),
+            [
+                "context-system/type-oneshot/host-KIOSK-%2A/os-build-lt-23000/c",
+                "context-system/type-oneshot/os-win10/b",
+            ]
+        );
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    fn user_filter(patterns: &[&str]) -> Filter<String> {
+        let mut filter = Filter::default();
+        for pattern in patterns {
+            let value = literal_value(pattern);
+            filter.insert(value.negated, value.value);
+        }
+        filter
+    }
+
+    #[test]
+    fn user_rules_match_the_mapped_upn() {
+        let config = IdentityConfig {
+            upn_suffix: Some("contoso.com".to_string()),
+            ..Default::default()
+        };
+        let provider = MappedIdentityProvider::new(
+            StaticIdentityProvider(Identity {
+                username: "jdoe".to_string(),
+                ..Default::default()
+            }),
+            &config,
+            &ExecutionContext::User,
+        );
+        let upn = provider.current_identity().unwrap().upn;
+
+        for patterns in [&["jdoe"][..], &["*@contoso.com"], &["j?oe", "admin"]] {
+            assert_eq!(
+                TaskFetcher::user_filter_mismatch(&user_filter(patterns), upn.as_deref()),
+                None,
+                "{:?}",
+                patterns
+            );
+        }
+
+        for patterns in [
+            &["*@fabrikam.com"][..],
+            &["!jdoe"],
+            &["jdoe@*", "!*@contoso.com"],
+        ] {
+            assert!(
+                TaskFetcher::user_filter_mismatch(&user_filter(patterns), upn.as_deref()).is_some(),
+                "{:?}",
+                patterns
+            );
+        }
+    }
+
+    fn entry() -> StackEntry {
+        StackEntry {
+            path: PathBuf::new(),
+            tasktype: None,
+            context: None,
+            depends_on: None,
+            user_filter: None,
+            group_filter: None,
+            attr_filter: None,
+            device_filter: None,
+            reboot_required: false,
+        }
+    }
+
+    fn apply(entry: &mut StackEntry, folder: &str) -> Result<(), RuleError> {
+        TaskFetcher::apply_folder_rule(entry, folder, &HashMap::new())
+    }
+
+    #[test]
+    fn depends_cannot_be_negated() {
+        let mut e = entry();
+
+        apply(&mut e, "depends-base+vpn%2Finstall").unwrap();
+        assert_eq!(
+            e.depends_on,
+            Some(HashSet::from([
+                "base".to_string(),
+                "vpn/install".to_string()
+            ]))
+        );
+
+        assert!(matches!(
+            apply(&mut e, "depends-!base"),
+            Err(RuleError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn reset_drops_inherited_rules() {
+        let mut e = entry();
+        for folder in [
+            "group-sales",
+            "user-jdoe",
+            "attr-department-IT",
+            "host-kiosk",
+            "os-win11",
+            "depends-base",
+        ] {
+            apply(&mut e, folder).unwrap();
+        }
+
+        apply(&mut e, "reset-group+depends").unwrap();
+        assert!(e.group_filter.is_none());
+        assert!(e.depends_on.is_none());
+        assert!(e.user_filter.is_some());
+
+        apply(&mut e, "reset-host").unwrap();
+        let kinds: Vec<&str> = e
+            .device_filter
+            .iter()
+            .flatten()
+            .flat_map(|f| f.include.iter().map(|r| r.kind()))
+            .collect();
+        assert_eq!(kinds, ["os"]);
+
+        apply(&mut e, "reset-all").unwrap();
+        assert!(e.user_filter.is_none());
+        assert!(e.attr_filter.is_none());
+        assert!(e.device_filter.is_none());
+    }
+
+    #[test]
+    fn reset_rejects_unknown_and_negated_kinds() {
+        assert!(matches!(
+            apply(&mut entry(), "reset-everything"),
+            Err(RuleError::InvalidValue(_))
+        ));
+        assert!(matches!(
+            apply(&mut entry(), "reset-!group"),
+            Err(RuleError::InvalidValue(_))
+        ));
+    }
+
+    #[test]
+    fn user_rules_need_a_upn() {
+        assert!(TaskFetcher::user_filter_mismatch(&user_filter(&["*"]), None).is_some());
     }
 }
@@ -1,12 +1,15 @@
 use crate::{
-    common::{get_appdata_local, get_programdata, get_upn, APP_NAME},
-    task::{ExecutionContext, Task, TaskType},
+    common::{get_system_install_path, get_user_install_path},
+    config::Config,
+    identity::Identity,
+    task::{ExecutionContext, FetchReport, Task, TaskType},
     task_fetcher::TaskFetcher,
 };
 use std::{
     collections::{HashMap, HashSet},
     error::Error,
     fs,
+    path::Path,
     process::Command,
 };
 
@@ -20,23 +23,35 @@ pub struct TaskRunner {
     execution_context: ExecutionContext,
 }
 
+/// The first field of the state, it can still be read if the rest changed between versions.
+#[derive(Deserialize)]
+struct DoneOneshotTasks {
+    done_oneshot_tasks: HashMap<String, String>,
+}
+
 use log::info;
 
 impl TaskRunner {
-    pub fn new(execution_context: ExecutionContext) -> Result<Self, Box<dyn Error>> {
-        let upn = get_upn();
+    pub fn new(
+        execution_context: ExecutionContext,
+        repo_path: &Path,
+        identity: Option<&Identity>,
+        config: &Config,
+    ) -> Result<Self, Box<dyn Error>> {
+        let upn = identity.and_then(|i| i.upn.clone());
         let (fetched_tasks, tasks_changed) =
-            TaskFetcher::fetch_tasks(execution_context.clone(), upn)?;
+            TaskFetcher::fetch_tasks(execution_context.clone(), repo_path, upn, config)?;
 
         match Self::restore_from_disk(&execution_context) {
             Some(mut restored_state) => {
-                if tasks_changed {
+                // a state of an older version only contains the done OneShot tasks
+                if tasks_changed || restored_state.task_list.is_empty() {
                     restored_state.next_task = 0;
                     restored_state.task_list = fetched_tasks.0;
 
                     let mut hs = HashSet::new();
                     for t in &restored_state.task_list {
-                        hs.insert(t.name.clone());
+                        hs.insert(t.id.clone());
                     }
 
                     let restored_tasknames: Vec<String> =
@@ -44,8 +59,7 @@ impl TaskRunner {
 
                     for task in restored_tasknames {
                         if !hs.contains(&task) {
-                            info!("Removing task \"{}\" from restored done_oneshot_tasks since it does not exist anymore.", task);
-                            restored_state.done_oneshot_tasks.remove(&task);
+                            restored_state.migrate_done_oneshot_task(&task);
                         }
                     }
                 }
@@ -63,50 +77,77 @@ impl TaskRunner {
         }
     }
 
-    fn restore_from_disk(execution_context: &ExecutionContext) -> Option<Self> {
+    /// States of older versions are keyed by the task name, the entry is kept for the task with this
+    /// name if there is exactly one. Otherwise the task does not exist anymore.
+    fn migrate_done_oneshot_task(&mut self, name: &str) {
+        let hash = self.done_oneshot_tasks.remove(name).unwrap();
+        let mut tasks = self.task_list.iter().filter(|t| t.name == name);
+
+        match (tasks.next(), tasks.next()) {
+            (Some(task), None) if !self.done_oneshot_tasks.contains_key(&task.id) => {
+                info!(
+                    "Keying done OneShot task \"{}\" by its id \"{}\"",
+                    name, task.id
+                );
+                self.done_oneshot_tasks.insert(task.id.clone(), hash);
+            }
+            _ => {
+                info!("Removing task \"{}\" from restored done_oneshot_tasks since it does not exist anymore.", name);
+            }
+        }
+    }
+
+    pub fn restore_from_disk(execution_context: &ExecutionContext) -> Option<Self> {
         match execution_context {
             ExecutionContext::System => {
-                let mut path = get_programdata().unwrap();
-                path.push(APP_NAME);
+                let mut path = get_system_install_path().unwrap();
                 path.push("state.bin");
 
                 if !path.exists() || !path.is_file() {
                     return None;
                 }
 
-                if let Ok(buf) = fs::read(path) {
-                    if let Ok(state) = bincode::deserialize(&buf) {
-                        return Some(state);
-                    }
-                }
+                let buf = fs::read(path).ok()?;
 
-                None
+                Self::deserialize_state(&buf, execution_context)
             }
             ExecutionContext::User => {
-                let mut path = get_appdata_local().unwrap();
-                path.push(APP_NAME);
+                let mut path = get_user_install_path().unwrap();
                 path.push("state.bin");
 
                 if !path.exists() || !path.is_file() {
                     return None;
                 }
 
-                if let Ok(buf) = fs::read(path) {
-                    if let Ok(state) = bincode::deserialize(&buf) {
-                        return Some(state);
-                    }
-                }
+                let buf = fs::read(path).ok()?;
 
-                None
+                Self::deserialize_state(&buf, execution_context)
             }
         }
     }
 
+    /// Falls back to the done OneShot tasks of a state written by another version, so a changed task
+    /// format does not run them again. bincode ignores the trailing fields.
+    fn deserialize_state(buf: &[u8], execution_context: &ExecutionContext) -> Option<Self> {
+        if let Ok(state) = bincode::deserialize(buf) {
+            return Some(state);
+        }
+
+        let legacy: DoneOneshotTasks = bincode::deserialize(buf).ok()?;
+        info!("Restored the done OneShot tasks of a state written by another version");
+
+        Some(TaskRunner {
+            done_oneshot_tasks: legacy.done_oneshot_tasks,
+            task_list: Vec::new(),
+            next_task: 0,
+            execution_context: execution_context.clone(),
+        })
+    }
+
     fn store_to_disk(&self) {
         match self.execution_context {
             ExecutionContext::System => {
-                let mut path = get_programdata().unwrap();
-                path.push(APP_NAME);
+                let mut path = get_system_install_path().unwrap();
 
                 if !path.exists() {
                     fs::create_dir_all(&path).unwrap();
@@ -118,8 +159,7 @@ impl TaskRunner {
                 fs::write(path, &buf).unwrap();
             }
             ExecutionContext::User => {
-                let mut path = get_appdata_local().unwrap();
-                path.push(APP_NAME);
+                let mut path = get_user_install_path().unwrap();
 
                 if !path.exists() {
                     fs::create_dir_all(&path).unwrap();
@@ -133,11 +173,10 @@ impl TaskRunner {
         }
     }
 
-    fn remove_disk_state(execution_context: &ExecutionContext) {
+    pub fn remove_disk_state(execution_context: &ExecutionContext) {
         match execution_context {
             ExecutionContext::System => {
-                let mut path = get_programdata().unwrap();
-                path.push(APP_NAME);
+                let mut path = get_system_install_path().unwrap();
                 path.push("state.bin");
 
                 if path.exists() {
@@ -145,8 +184,7 @@ impl TaskRunner {
                 }
             }
             ExecutionContext::User => {
-                let mut path = get_appdata_local().unwrap();
-                path.push(APP_NAME);
+                let mut path = get_user_install_path().unwrap();
                 path.push("state.bin");
 
                 if path.exists() {
@@ -156,16 +194,19 @@ impl TaskRunner {
         }
     }
 
-    pub fn run(&mut self) {
+    /// Runs the remaining tasks, returns false if a task failed.
+    pub fn run(&mut self) -> bool {
+        let mut succeeded = true;
+
         while self.next_task < self.task_list.len() {
             let task = &self.task_list[self.next_task];
 
             if matches!(task.type_, TaskType::OneShot) {
-                if let Some(old_hash) = self.done_oneshot_tasks.get(&task.name) {
+                if let Some(old_hash) = self.done_oneshot_tasks.get(&task.id) {
                     if old_hash == &task.hash {
                         info!(
                             "Skipping execution of OneShot task: {}, since it has not changed",
-                            task.name
+                            task.id
                         );
                         self.next_task += 1;
                         continue;
@@ -173,17 +214,18 @@ impl TaskRunner {
                 }
             }
 
-            info!("Running task \"{}\"", task.name);
+            info!("Running task \"{}\"", task.id);
 
             if !task.run() {
                 info!("TASK EXECUTION FAILED, GIVING UP!");
                 Self::remove_disk_state(&self.execution_context);
+                succeeded = false;
                 break;
             }
 
             if matches!(task.type_, TaskType::OneShot) {
                 self.done_oneshot_tasks
-                    .insert(task.name.clone(), task.hash.clone());
+                    .insert(task.id.clone(), task.hash.clone());
             }
 
             self.next_task += 1;
@@ -197,11 +239,86 @@ impl TaskRunner {
 
         self.store_to_disk();
 
-        if self.next_task >= self.task_list.len() {
-            info!("ALL TASKS EXECUTED SUCCESSFULLY!");
+        if !succeeded {
+            return false;
         }
+
+        info!("ALL TASKS EXECUTED SUCCESSFULLY!");
+
+        true
     }
 
+    pub fn print_status(&self) {
+        println!("Execution context: {:?}", self.execution_context);
+        println!(
+            "Progress: {}/{} tasks of the last run",
+            self.next_task.min(self.task_list.len()),
+            self.task_list.len()
+        );
+
+        for (i, task) in self.task_list.iter().enumerate() {
+            let state = if i < self.next_task {
+                "done"
+            } else {
+                "pending"
+            };
+            println!("  [{}] {} ({:?})", state, task.id, task.type_);
+        }
+
+        println!("Completed OneShot tasks:");
+        let mut done: Vec<&String> = self.done_oneshot_tasks.keys().collect();
+        done.sort();
+        for name in done {
+            println!("  {}", name);
+        }
+    }
+
+    /// Prints the tasks in execution order and whether they would run.
+    pub fn print_plan(execution_context: &ExecutionContext, tasks: &[Task], report: &FetchReport) {
+        let done_oneshot_tasks = Self::restore_from_disk(execution_context)
+            .map(|s| s.done_oneshot_tasks)
+            .unwrap_or_default();
+
+        println!("Execution context: {:?}", execution_context);
+        println!("Group membership: {}", report.group_membership);
+
+        for task in tasks {
+            let decision = match task.type_ {
+                TaskType::OneShot if done_oneshot_tasks.get(&task.id) == Some(&task.hash) => {
+                    "skip (unchanged OneShot)"
+                }
+                _ => "run",
+            };
+
+            println!(
+                "  {:<28} {:<8} {}{}",
+                decision,
+                format!("{:?}", task.type_),
+                task.id,
+                if task.reboot_required {
+                    " (reboot)"
+                } else {
+                    ""
+                }
+            );
+        }
+
+        for skipped in &report.skipped {
+            println!(
+                "  {:<28} {:<8} {} ({})",
+                "skip (filtered)", "", skipped.id, skipped.reason
+            );
+        }
+
+        for invalid in &report.invalid {
+            println!(
+                "  {:<28} {:<8} {} ({})",
+                "skip (invalid)", "", invalid.path, invalid.error
+            );
+        }
+    }
+
+    #[cfg(windows)]
     fn do_reboot() {
         Command::new("powershell.exe")
             .arg("Restart-Computer -Force")
@@ -210,4 +327,14 @@ impl TaskRunner {
             .wait()
             .unwrap();
     }
+
+    #[cfg(target_os = "linux")]
+    fn do_reboot() {
+        Command::new("systemctl")
+            .arg("reboot")
+            .spawn()
+            .unwrap()
+            .wait()
+            .unwrap();
+    }
 }